@@ -0,0 +1,5 @@
+pub mod trackers;
+pub mod torrent;
+pub mod peers;
+pub mod pieces;
+pub mod stats;
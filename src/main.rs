@@ -1,53 +1,94 @@
 use tokio::time::{sleep, Duration};
 use sha1::{self, Digest};
-use std::{collections::HashSet, net::SocketAddr, sync::Arc};
-use tokio::sync::Mutex;
+use std::{path::{Path, PathBuf}, sync::Arc};
+use tokio::sync::{Mutex, Semaphore};
 
-mod trackers;
-mod torrent;
-mod peers;
-mod pieces;
+use torrent_rs::torrent;
+use torrent_rs::trackers::{Tracker, HttpTracker, TrackerResponse};
+use torrent_rs::peers::{parse_peers, PeerManager};
+use torrent_rs::peers::ip_filter::IpFilter;
+use torrent_rs::peers::peer_manager::{DEFAULT_GLOBAL_CONNECTIONS, DEFAULT_TORRENT_CONNECTIONS};
+use torrent_rs::peers::rate_limit::{Rate, SessionLimits};
+use torrent_rs::stats::TransferStats;
 
-use trackers::{Tracker, HttpTracker, TrackerResponse};
-use peers::{parse_peers, PeerConnection};
+use torrent_rs::pieces::{file_manager::FileManager, picker::RarestFirstPicker, piece_manager::PieceManager};
+use torrent_rs::pieces::piece_manager::{BufferBudget, DEFAULT_MAX_BUFFERED};
+use torrent_rs::pieces::resume::{self, ResumeData};
+use torrent_rs::pieces::storage::{AllocationMode, Storage};
 
-use crate::pieces::{file_manager::FileManager, piece_manager::PieceManager};
-
-type PeerPool = Arc<Mutex<HashSet<SocketAddr>>>;
-
-// update peer pool
+// feed the peer manager
 
 async fn run_tracker(
     tracker: Box<dyn Tracker + Send + Sync>,
     info_hash: Arc<Vec<u8>>,
-    peer_pool: PeerPool,
-    pm: Arc<Mutex<PieceManager>>
+    peers: Arc<PeerManager>,
 ) -> anyhow::Result<()> {
         loop {
         let resp: TrackerResponse = tracker.announce(&info_hash).await?;
-        let peers = parse_peers(&resp.peers);
-
-        let mut pool = peer_pool.lock().await;
-
-        for peer_addr in peers {
-            if pool.insert(peer_addr) {
-                let pm_clone = pm.clone();
-                tokio::spawn({
-                    let info_clone = info_hash.clone();
-                    async move {
-                        if let Ok(conn) = PeerConnection::new(peer_addr, info_clone, pm_clone).await {
-                            if let Err(e) = conn.start().await {
-                                eprintln!("Peer {} failed: {:?}", peer_addr, e);
-                            }
-                        }
+        peers.add_candidates(parse_peers(&resp.peers));
+
+        sleep(Duration::from_secs(resp.interval.unwrap_or(120))).await;
+    }
+}
+
+struct Options {
+    // download directory, the working directory unless given
+    save_path: PathBuf,
+    allocation: AllocationMode,
+    incomplete_suffix: bool,
+    completed_dir: Option<PathBuf>,
+    // session-wide, bytes per second; 0 is unlimited
+    upload_limit: Rate,
+    download_limit: Rate,
+    // eMule ipfilter.dat or P2P blocklist
+    ip_filter: Option<PathBuf>,
+}
+
+impl Options {
+    /// `[save_path] [--allocation lazy|sparse|full] [--part-suffix] [--completed-dir DIR]
+    /// [--upload-limit KIB_PER_SEC] [--download-limit KIB_PER_SEC] [--ip-filter FILE]`
+    fn parse(mut args: impl Iterator<Item = String>) -> anyhow::Result<Self> {
+        let mut options = Options {
+            save_path: PathBuf::from("."),
+            allocation: AllocationMode::Sparse,
+            incomplete_suffix: false,
+            completed_dir: None,
+            upload_limit: 0,
+            download_limit: 0,
+            ip_filter: None,
+        };
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--allocation" => {
+                    let mode = args.next().ok_or_else(|| anyhow::anyhow!("--allocation needs a mode"))?;
+                    options.allocation = mode.parse()?;
+                }
+                "--part-suffix" => options.incomplete_suffix = true,
+                "--completed-dir" => {
+                    let dir = args.next().ok_or_else(|| anyhow::anyhow!("--completed-dir needs a directory"))?;
+                    options.completed_dir = Some(PathBuf::from(dir));
+                }
+                "--upload-limit" | "--download-limit" => {
+                    let kib: Rate = args.next().ok_or_else(|| anyhow::anyhow!("{arg} needs a rate in KiB/s"))?.parse()?;
+                    if arg == "--upload-limit" {
+                        options.upload_limit = kib * 1024;
+                    } else {
+                        options.download_limit = kib * 1024;
                     }
-                });
+                }
+                "--ip-filter" => {
+                    let path = args.next().ok_or_else(|| anyhow::anyhow!("--ip-filter needs a file"))?;
+                    options.ip_filter = Some(PathBuf::from(path));
+                }
+                _ if arg.starts_with("--") => anyhow::bail!("unknown option {arg}"),
+                _ => options.save_path = PathBuf::from(arg),
             }
         }
-        
-        sleep(Duration::from_secs(resp.interval.unwrap_or(120))).await;
+        Ok(options)
     }
 }
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let torrent = torrent::load_torrent("torrents/CSVFILES-0f97ce1fa054ad5269bd675e3ad9ad599cd67e66.torrent")?;
@@ -55,10 +96,72 @@ async fn main() -> anyhow::Result<()> {
     let info_hash: [u8; 20] = sha1::Sha1::digest(&info_bytes).into();
     let info_hash_vec = Arc::new(info_hash.to_vec());
 
-    let peer_pool: PeerPool = Arc::new(Mutex::new(HashSet::new()));
+    let options = Options::parse(std::env::args().skip(1))?;
+    let mut fm = FileManager::new(&torrent.info, &options.save_path)?;
+    let mut completed_dir = options.completed_dir.clone();
+    // a finished download was moved on in an earlier run; seed it from there
+    if let Some(dir) = &options.completed_dir {
+        let moved = FileManager::new(&torrent.info, dir)?;
+        if !fm.files_exist() && moved.files_exist() {
+            println!("Found completed download in {}", dir.display());
+            fm = moved;
+            completed_dir = None;
+        }
+    }
+    if options.incomplete_suffix {
+        fm = fm.with_incomplete_suffix();
+    }
+    let fm = Arc::new(fm);
+    let mut pm = PieceManager::new(torrent.info.piece_length, torrent.total_length(), &torrent.info.pieces, fm.clone(), Box::new(RarestFirstPicker));
 
-    let fm = FileManager::new(&torrent.info)?;
-    let pm = Arc::new(Mutex::new(PieceManager::new(torrent.info.piece_length, torrent.total_length(), &torrent.info.pieces, fm)));
+    let resume_path = resume::resume_path(&options.save_path, &info_hash);
+    let resume_data = match ResumeData::load(&resume_path) {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("Failed to read resume data: {:?}", e);
+            None
+        }
+    };
+    match resume_data {
+        Some(data) if data.is_valid_for(&info_hash, pm.num_pieces, &pm.file_states()?) => pm.restore(&data),
+        _ => {
+            println!("No usable resume data, checking existing files");
+            let valid = pm.check_pieces(|p| {
+                if p.checked % 100 == 0 || p.checked == p.total {
+                    println!("Checked {}/{} pieces ({} valid)", p.checked, p.total, p.valid);
+                }
+            });
+            println!("{valid} pieces already on disk");
+        }
+    }
+    // after the resume check, so growing the files does not make it look stale
+    fm.allocate(options.allocation)?;
+    pm.set_completed_dir(completed_dir);
+    pm.move_if_finished();
+
+    // shared by every torrent in the session
+    let buffer_budget = BufferBudget::new(DEFAULT_MAX_BUFFERED);
+    pm.set_buffer_budget(buffer_budget);
+    let pm = Arc::new(Mutex::new(pm));
+    PieceManager::spawn_completion_loop(pm.clone());
+
+    let global_slots = Arc::new(Semaphore::new(DEFAULT_GLOBAL_CONNECTIONS));
+    let session_limits = Arc::new(SessionLimits::new(options.upload_limit, options.download_limit));
+    let session_stats = TransferStats::new();
+    let peers = PeerManager::new(
+        info_hash_vec.clone(),
+        pm.clone(),
+        global_slots,
+        DEFAULT_TORRENT_CONNECTIONS,
+        session_limits,
+        session_stats.clone(),
+    );
+    if let Some(path) = &options.ip_filter {
+        let filter = IpFilter::load(path)?;
+        println!("Loaded {} blocked ranges from {}", filter.num_ranges(), path.display());
+        peers.set_ip_filter(Arc::new(filter));
+    }
+    peers.spawn();
 
     let tracker_objs: Vec<Box<dyn Tracker + Send + Sync>> = torrent
         .announce_list
@@ -71,22 +174,46 @@ async fn main() -> anyhow::Result<()> {
 
     for tracker in tracker_objs {
         let info_clone = Arc::clone(&info_hash_vec);
-        let pool_clone = Arc::clone(&peer_pool);
+        let peers_clone = Arc::clone(&peers);
 
-        let pm_clone = pm.clone();
         tokio::spawn(async move {
-            if let Err(e) = run_tracker(tracker, info_clone, pool_clone, pm_clone).await {
+            if let Err(e) = run_tracker(tracker, info_clone, peers_clone).await {
                 eprintln!("Tracker task failed: {:?}", e);
             }
         });
     }
 
-    // monitor peer pool
+    // monitor peers and checkpoint resume data
+    let mut monitor = tokio::time::interval(Duration::from_secs(30));
     loop {
-        {
-            let pool = peer_pool.lock().await;
-            println!("Currently {} peers", pool.len());
+        tokio::select! {
+            _ = monitor.tick() => {
+                println!("Currently {} peers connected, {} known", peers.num_connected(), peers.num_candidates());
+                let stats = peers.stats().await;
+                println!(
+                    "Down {:.1} KiB/s, up {:.1} KiB/s, {} bytes left, ETA {}",
+                    stats.transfer.download_rate / 1024.0,
+                    stats.transfer.upload_rate / 1024.0,
+                    stats.left,
+                    stats.eta.map_or("unknown".to_string(), |eta| format!("{}s", eta.as_secs())),
+                );
+                save_resume(&pm, &info_hash, &resume_path).await;
+            }
+
+            _ = tokio::signal::ctrl_c() => {
+                let data = PieceManager::stop(&pm, &info_hash).await;
+                if let Err(e) = data.and_then(|data| data.save(&resume_path)) {
+                    eprintln!("Failed to save resume data: {:?}", e);
+                }
+                return Ok(());
+            }
         }
-        sleep(Duration::from_secs(30)).await;
+    }
+}
+
+async fn save_resume(pm: &Arc<Mutex<PieceManager>>, info_hash: &[u8], path: &Path) {
+    let data = pm.lock().await.resume_data(info_hash);
+    if let Err(e) = data.and_then(|data| data.save(path)) {
+        eprintln!("Failed to save resume data: {:?}", e);
     }
 }
\ No newline at end of file
@@ -0,0 +1,187 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+/// Blocked address ranges, loaded from eMule `ipfilter.dat` or PeerGuardian
+/// P2P lists.
+///
+/// Ranges are kept sorted and merged, so a lookup is a binary search however
+/// long the list is.
+#[derive(Default, Debug)]
+pub struct IpFilter {
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+}
+
+// eMule access levels below this block the range
+const EMULE_BLOCK_LEVEL: u32 = 128;
+
+impl IpFilter {
+    /// Reads a list in either format; lines that parse in neither are skipped.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let bytes = std::fs::read(path)?;
+        let (filter, skipped) = Self::parse(&String::from_utf8_lossy(&bytes));
+        if skipped > 0 {
+            eprintln!("Skipped {} unreadable lines in {}", skipped, path.display());
+        }
+        Ok(filter)
+    }
+
+    /// Parses list text, returning the filter and how many lines were skipped.
+    pub fn parse(text: &str) -> (Self, usize) {
+        let mut filter = Self::default();
+        let mut skipped = 0;
+
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("//") { continue; }
+
+            match parse_emule(line).or_else(|| parse_p2p(line)) {
+                Some(Some((start, end))) => {
+                    if !filter.add_range(start, end) { skipped += 1; }
+                }
+                Some(None) => {} // allowed by its access level
+                None => skipped += 1,
+            }
+        }
+
+        filter.merge();
+        (filter, skipped)
+    }
+
+    /// Blocks `start..=end`. Both ends must be of the same family. Call
+    /// [`IpFilter::merge`] once done adding.
+    pub fn add_range(&mut self, start: IpAddr, end: IpAddr) -> bool {
+        match (start, end) {
+            (IpAddr::V4(s), IpAddr::V4(e)) => self.v4.push((u32::from(s).min(u32::from(e)), u32::from(s).max(u32::from(e)))),
+            (IpAddr::V6(s), IpAddr::V6(e)) => self.v6.push((u128::from(s).min(u128::from(e)), u128::from(s).max(u128::from(e)))),
+            _ => return false,
+        }
+        true
+    }
+
+    /// Sorts the ranges and folds overlapping or adjacent ones together.
+    pub fn merge(&mut self) {
+        merge_ranges(&mut self.v4, |ip| ip.saturating_add(1));
+        merge_ranges(&mut self.v6, |ip| ip.saturating_add(1));
+    }
+
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => contains(&self.v4, u32::from(ip)),
+            // mapped IPv4 addresses are filtered by the IPv4 list
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(v4) => contains(&self.v4, u32::from(v4)),
+                None => contains(&self.v6, u128::from(ip)),
+            },
+        }
+    }
+
+    pub fn num_ranges(&self) -> usize {
+        self.v4.len() + self.v6.len()
+    }
+}
+
+fn merge_ranges<T: Ord + Copy>(ranges: &mut Vec<(T, T)>, next: fn(T) -> T) {
+    ranges.sort_unstable();
+    let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+    for &(start, end) in ranges.iter() {
+        match merged.last_mut() {
+            Some(last) if start <= next(last.1) => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    *ranges = merged;
+}
+
+fn contains<T: Ord + Copy>(ranges: &[(T, T)], ip: T) -> bool {
+    // first range starting after `ip`; the one before it is the only candidate
+    let i = ranges.partition_point(|&(start, _)| start <= ip);
+    i > 0 && ranges[i - 1].1 >= ip
+}
+
+/// `start - end , level , description`. `None` if the line is not in this
+/// format, `Some(None)` if its level allows the range.
+fn parse_emule(line: &str) -> Option<Option<(IpAddr, IpAddr)>> {
+    let mut fields = line.splitn(3, ',');
+    let range = fields.next()?;
+    let level: u32 = fields.next()?.trim().parse().ok()?;
+    let (start, end) = range.split_once('-')?;
+    let range = (parse_ip(start.trim())?, parse_ip(end.trim())?);
+    Some((level < EMULE_BLOCK_LEVEL).then_some(range))
+}
+
+/// `description:start-end`. The description may itself contain colons, and
+/// so may IPv6 addresses, so every colon is tried as the separator.
+fn parse_p2p(line: &str) -> Option<Option<(IpAddr, IpAddr)>> {
+    line.match_indices(':').find_map(|(i, _)| {
+        let (start, end) = line[i + 1..].split_once('-')?;
+        Some(Some((parse_ip(start.trim())?, parse_ip(end.trim())?)))
+    })
+}
+
+// eMule lists pad IPv4 octets with zeros, which `Ipv4Addr`'s parser rejects
+fn parse_ip(s: &str) -> Option<IpAddr> {
+    if s.contains(':') {
+        return s.parse::<Ipv6Addr>().ok().map(IpAddr::V6);
+    }
+    let mut octets = [0u8; 4];
+    let mut parts = s.split('.');
+    for octet in &mut octets {
+        *octet = parts.next()?.parse().ok()?;
+    }
+    if parts.next().is_some() { return None; }
+    Some(IpAddr::V4(Ipv4Addr::from(octets)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_emule_levels() {
+        let (filter, skipped) = IpFilter::parse(
+            "# comment\n\
+             001.002.003.000 - 001.002.003.255 , 000 , blocked\n\
+             005.000.000.000 - 005.000.000.010 , 200 , allowed\n",
+        );
+        assert_eq!(skipped, 0);
+        assert_eq!(filter.num_ranges(), 1);
+        assert!(filter.is_blocked(ip("1.2.3.4")));
+        assert!(!filter.is_blocked(ip("1.2.4.0")));
+        assert!(!filter.is_blocked(ip("5.0.0.1")));
+    }
+
+    #[test]
+    fn parses_p2p_lines_with_colons() {
+        let (filter, skipped) = IpFilter::parse(
+            "Some org: with a colon:10.0.0.0-10.0.0.255\n\
+             v6 range:2001:db8::-2001:db8::ffff\n\
+             not a range\n",
+        );
+        assert_eq!(skipped, 1);
+        assert!(filter.is_blocked(ip("10.0.0.7")));
+        assert!(filter.is_blocked(ip("2001:db8::1")));
+        assert!(!filter.is_blocked(ip("2001:db8::1:0")));
+        // mapped addresses go by the IPv4 list
+        assert!(filter.is_blocked(ip("::ffff:10.0.0.7")));
+    }
+
+    #[test]
+    fn merges_overlapping_and_adjacent_ranges() {
+        let (filter, _) = IpFilter::parse(
+            "a:1.0.0.0-1.0.0.10\n\
+             b:1.0.0.5-1.0.0.20\n\
+             c:1.0.0.21-1.0.0.30\n\
+             d:1.0.0.40-1.0.0.50\n",
+        );
+        assert_eq!(filter.num_ranges(), 2);
+        assert!(filter.is_blocked(ip("1.0.0.25")));
+        assert!(!filter.is_blocked(ip("1.0.0.35")));
+        assert!(filter.is_blocked(ip("1.0.0.50")));
+        assert!(!filter.is_blocked(ip("1.0.0.51")));
+    }
+}
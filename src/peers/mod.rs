@@ -1,6 +1,11 @@
+pub mod ip_filter;
 pub mod peer;
 pub mod peer_connection;
+pub mod peer_manager;
+pub mod rate_limit;
+pub mod registry;
 
 pub use peer::parse_peers;
 pub use peer_connection::PeerConnection;
+pub use peer_manager::PeerManager;
 
@@ -12,7 +12,8 @@ use serde_bencode::value::Value;
 
 pub struct Peer {
     pub addr: std::net::SocketAddr,
-    pub peer_id: Option<String>,
+    // raw bytes; most clients put binary data after the prefix
+    pub peer_id: Option<[u8; 20]>,
     pub downloaded: u64,
     pub uploaded: u64,
     pub left: u64,
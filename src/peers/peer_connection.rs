@@ -1,112 +1,340 @@
 use crate::peers::peer::Peer;
-use crate::pieces::piece_manager::PieceManager;
+use crate::peers::rate_limit::ConnectionLimits;
+use crate::peers::registry::{ConnectionError, PeerRegistry, Registration};
+use crate::stats::ConnectionStats;
+use crate::pieces::disk_io::{DiskIo, ReadQueueFull};
+use crate::pieces::piece_manager::{BlockError, PieceEvent, PieceManager};
+use std::collections::HashSet;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use anyhow::Ok;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::sync::Mutex;
+use tokio::sync::{broadcast, Mutex};
 
 const BLOCK_SIZE: usize = 16384;
 const MAX_OUTGOING: u8 = 255;
+const READ_CHUNK: usize = 32 * 1024;
+// largest request we serve; clients ask for 16 KiB, a few for 32 KiB
+const MAX_REQUEST_LENGTH: usize = 128 * 1024;
+// BEP 6, last reserved byte of the handshake
+const FAST_EXTENSION_BIT: u8 = 0x04;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(20);
+// BEP 3 suggests two minutes between keep-alives
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(120);
+/// Inbound silence after which a connection is dropped, unless configured otherwise.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(180);
+// how long neither side may be interested before the slot goes to someone else
+const NOT_INTERESTED_TIMEOUT: Duration = Duration::from_secs(60);
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_secs(5);
+// client and version at the start of our peer IDs
+const PEER_ID_PREFIX: &[u8; 8] = b"-TR1012-";
 
 pub struct PeerConnection {
     peer: Peer,
     stream: tokio::net::TcpStream,
     bitfield: Vec<bool>,
+    // pieces we told the peer we have
+    announced: Vec<bool>,
     info_hash: Arc<Vec<u8>>,
-    peer_id: String,
+    peer_id: [u8; 20],
 
     // buffers
-    length_buf: [u8; 4],
+    read_buf: Vec<u8>,
+    inbox: Vec<u8>,
     msg_buf: Vec<u8>,
     handshake_buf: [u8; 68],
 
     // states
     am_choked: bool,
     peer_choked: bool,
+    peer_interested: bool,
     am_interested: bool,
-
-    in_flight: u8,
+    fast_extension: bool,
+
+    limits: ConnectionLimits,
+    stats: ConnectionStats,
+    registry: Option<Arc<PeerRegistry>>,
+    registration: Option<Registration>,
+
+    // timers
+    last_sent: Instant,
+    last_received: Instant,
+    idle_timeout: Duration,
+    // since when neither side has been interested
+    uninterested_since: Option<Instant>,
+
+    // (piece index, begin) of every request still awaiting a block
+    pending: HashSet<(usize, usize)>,
+    // requests we cancelled that the peer may already have answered
+    cancelled: HashSet<(usize, usize)>,
     piece_manager: Arc<Mutex<PieceManager>>,
+    disk: DiskIo,
+    events: broadcast::Receiver<PieceEvent>,
+}
+
+/// A peer ID for one session: the client prefix and 12 random bytes, so two
+/// instances of this client can tell each other apart.
+pub fn generate_peer_id() -> [u8; 20] {
+    let mut id = [0; 20];
+    id[..8].copy_from_slice(PEER_ID_PREFIX);
+    getrandom::fill(&mut id[8..]).expect("no system random source");
+    id
+}
+
+/// Unpacks a bitfield message payload, high bit first. The length must match
+/// the torrent exactly and bits past the last piece must be zero.
+fn parse_bitfield(payload: &[u8], num_pieces: usize) -> anyhow::Result<Vec<bool>> {
+    let expected_len = num_pieces.div_ceil(8);
+    if payload.len() != expected_len {
+        anyhow::bail!("{} bytes long, expected {}", payload.len(), expected_len);
+    }
+
+    let spare_bits = expected_len * 8 - num_pieces;
+    if let Some(&last) = payload.last()
+        && last & ((1u16 << spare_bits) - 1) as u8 != 0 {
+        anyhow::bail!("spare bits set");
+    }
+
+    Ok((0..num_pieces).map(|i| payload[i / 8] & (0x80 >> (i % 8)) != 0).collect())
 }
 
 impl PeerConnection {
-    pub async fn new(peer_addr: std::net::SocketAddr, info_hash: Arc<Vec<u8>>, pm: Arc<Mutex<PieceManager>>) -> anyhow::Result<Self> {
-        let peer = Peer::new(peer_addr);
-        let stream = tokio::net::TcpStream::connect(peer_addr).await?;
-        let num_pieces = {
-            pm.lock().await.num_pieces
+    pub async fn new(
+        peer_addr: std::net::SocketAddr,
+        info_hash: Arc<Vec<u8>>,
+        peer_id: [u8; 20],
+        pm: Arc<Mutex<PieceManager>>,
+    ) -> anyhow::Result<Self> {
+        let mut peer = Peer::new(peer_addr);
+        let stream = tokio::time::timeout(CONNECT_TIMEOUT, tokio::net::TcpStream::connect(peer_addr))
+            .await
+            .map_err(|_| anyhow::anyhow!("connecting to {} timed out", peer_addr))??;
+        let (num_pieces, events, disk) = {
+            let pm = pm.lock().await;
+            // until it tells us otherwise the peer has nothing
+            peer.left = pm.bytes_missing(&vec![false; pm.num_pieces]);
+            (pm.num_pieces, pm.subscribe(), pm.disk())
         };
         let bitfield = vec![false; num_pieces];
 
-        let peer_id: String = String::from("-TR1012-123456789012");
 
         Ok(PeerConnection {
             peer,
             stream,
+            announced: vec![false; bitfield.len()],
             bitfield,
             info_hash,
             peer_id,
-            length_buf: [0; 4],
+            read_buf: vec![0; READ_CHUNK],
+            inbox: Vec::new(),
             msg_buf: Vec::new(),
             handshake_buf: [0; 68],
             am_choked: true,
             peer_choked: true,
+            peer_interested: false,
             am_interested: false,
-            in_flight: 0,
+            fast_extension: false,
+            limits: ConnectionLimits::default(),
+            stats: ConnectionStats::default(),
+            registry: None,
+            registration: None,
+            last_sent: Instant::now(),
+            last_received: Instant::now(),
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            uninterested_since: Some(Instant::now()),
+            pending: HashSet::new(),
+            cancelled: HashSet::new(),
             piece_manager: pm.clone(),
+            disk,
+            events,
         })
     }
 
+    /// Drops the connection after `timeout` without hearing from the peer.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Counts the connection's traffic against `limits`.
+    pub fn with_rate_limits(mut self, limits: ConnectionLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Adds the connection's traffic to `stats`.
+    pub fn with_stats(mut self, stats: ConnectionStats) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// Checks the remote peer ID against the other connections in `registry`.
+    pub fn with_registry(mut self, registry: Arc<PeerRegistry>) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
     pub async fn start(mut self) -> anyhow::Result<()> {
-        if let Err(e) = self.perform_handshake().await {
+        let handshake = tokio::time::timeout(HANDSHAKE_TIMEOUT, self.perform_handshake())
+            .await
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out")));
+        if let Err(e) = handshake {
             eprintln!("Handshake failed for {}: {:?}", self.peer.addr, e);
+            return Err(e);
         }
         println!("Handshake successful for {}", self.peer.addr);
 
+        let remote_id: [u8; 20] = self.handshake_buf[48..68].try_into()?;
+        self.peer.peer_id = Some(remote_id);
+        if let Some(registry) = &self.registry {
+            // every connection we make is outgoing
+            self.registration = Some(registry.register(remote_id, true)?);
+        }
+
+        self.send_bitfield().await?;
+
+        let result = self.run().await;
+
+        // hand whatever we still had outstanding back to the other peers
+        {
+            let mut pm = self.piece_manager.lock().await;
+            pm.release_requests(self.peer.addr);
+            pm.remove_peer_bitfield(&self.bitfield);
+        }
+        result
+    }
+
+    async fn run(&mut self) -> anyhow::Result<()> {
+        let mut housekeeping = tokio::time::interval(HOUSEKEEPING_INTERVAL);
+        let replaced = self.registration.as_ref().map(|r| r.close.clone());
         loop {
-            match self.read_message_length().await? {
-                None => {
-                    // keep conn alive
-                    continue;
+            tokio::select! {
+                read = self.stream.read(&mut self.read_buf) => {
+                    let n = read?;
+                    if n == 0 {
+                        return Err(anyhow::anyhow!("{} closed the connection", self.peer.addr));
+                    }
+                    self.last_received = Instant::now();
+                    self.inbox.extend_from_slice(&self.read_buf[..n]);
+                    // holding off the next read lets TCP slow the sender down
+                    self.limits.download(n).await;
+
+                    while self.next_message().await? {
+                        self.handle_message().await?;
+                    }
                 }
-                Some(len) => {
-                    self.read_message_body(len).await?;
+
+                event = self.events.recv() => {
+                    match event {
+                        std::result::Result::Ok(event) => self.handle_event(event).await?,
+                        Err(broadcast::error::RecvError::Lagged(_)) => self.resync().await?,
+                        Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                    }
                 }
+
+                _ = housekeeping.tick() => self.check_timers().await?,
+
+                _ = async { match &replaced {
+                    Some(close) => close.notified().await,
+                    None => std::future::pending().await,
+                } } => {
+                    return Err(ConnectionError::Duplicate.into());
+                }
+            }
+        }
+    }
+
+    /// Sends keep-alives and gives up on connections that are dead or useless.
+    async fn check_timers(&mut self) -> anyhow::Result<()> {
+        let now = Instant::now();
+        if now - self.last_received >= self.idle_timeout {
+            anyhow::bail!("{} has been silent for {:?}", self.peer.addr, self.idle_timeout);
+        }
+
+        if self.am_interested || self.peer_interested {
+            self.uninterested_since = None;
+        } else {
+            let since = *self.uninterested_since.get_or_insert(now);
+            if now - since >= NOT_INTERESTED_TIMEOUT {
+                anyhow::bail!("neither side is interested in {}", self.peer.addr);
             }
         }
+
+        if now - self.last_sent >= KEEP_ALIVE_INTERVAL {
+            self.send(&0u32.to_be_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    /// Every message after the handshake goes out through here.
+    async fn send(&mut self, buf: &[u8]) -> anyhow::Result<()> {
+        self.send_message(buf, 0).await
+    }
+
+    /// `payload` is how many of the bytes are piece data.
+    async fn send_message(&mut self, buf: &[u8], payload: usize) -> anyhow::Result<()> {
+        self.limits.upload(buf.len()).await;
+        self.stream.write_all(buf).await?;
+        self.stats.record_upload(payload, buf.len() - payload);
+        self.last_sent = Instant::now();
+        Ok(())
     }
 
     async fn perform_handshake(&mut self) -> anyhow::Result<()> {
         self.handshake_buf[0] = 19; // pstrlen 
         self.handshake_buf[1..20].copy_from_slice(b"BitTorrent protocol");
         self.handshake_buf[20..28].fill(0);
+        self.handshake_buf[27] |= FAST_EXTENSION_BIT;
         self.handshake_buf[28..48].copy_from_slice(&self.info_hash);
-        self.handshake_buf[48..68].copy_from_slice(self.peer_id.as_bytes());
+        self.handshake_buf[48..68].copy_from_slice(&self.peer_id);
 
         self.stream.write_all(&self.handshake_buf).await?;
+        self.stats.record_upload(0, self.handshake_buf.len());
         self.stream.read_exact(&mut self.handshake_buf).await?;
+        self.stats.record_download(0, self.handshake_buf.len());
 
         if self.handshake_buf[28..48] != self.info_hash[..] {
             return Err(anyhow::anyhow!("Info hash mismatch"));
         }
+        self.fast_extension = self.handshake_buf[27] & FAST_EXTENSION_BIT != 0;
         Ok(())
     }
 
-    async fn read_message_length(&mut self) -> anyhow::Result<Option<u32>> {
-        self.stream.read_exact(&mut self.length_buf).await?;
-        let length = u32::from_be_bytes(self.length_buf);
+    /// Moves the next complete message out of `inbox` into `msg_buf`.
+    async fn next_message(&mut self) -> anyhow::Result<bool> {
+        loop {
+            if self.inbox.len() < 4 { return Ok(false); }
+
+            let length = u32::from_be_bytes(self.inbox[..4].try_into().unwrap()) as usize;
+            if length == 0 {
+                // keep conn alive
+                self.inbox.drain(..4);
+                self.stats.record_download(0, 4);
+                continue;
+            }
+            if self.inbox.len() < 5 { return Ok(false); }
 
-        if length == 0 { Ok(None) }
-        else { Ok(Some(length)) }
-    }
+            // nothing legitimate is bigger, and we would buffer it all before looking
+            let max_length = if self.inbox[4] == 5 { 1 + self.bitfield.len().div_ceil(8) } else { 9 + MAX_REQUEST_LENGTH };
+            if length > max_length {
+                self.piece_manager.lock().await.penalise(self.peer.addr.ip());
+                anyhow::bail!("{} sent a {} byte message with id {}", self.peer.addr, length, self.inbox[4]);
+            }
+            if self.inbox.len() < 4 + length { return Ok(false); }
 
-    async fn read_message_body(&mut self, length: u32) -> anyhow::Result<()> {
-        // read 4-byte length prefix
-        
-        self.msg_buf.resize(length as usize, 0);
-        self.stream.read_exact(&mut self.msg_buf).await?;
-        self.handle_message().await?;
-        Ok(())
+            // block data in a piece message is payload, everything else overhead
+            let payload = if self.inbox[4] == 7 { length.saturating_sub(9) } else { 0 };
+            self.stats.record_download(payload, 4 + length - payload);
+            if payload > 0 {
+                self.peer.downloaded += payload as u64;
+            }
+
+            self.msg_buf.clear();
+            self.msg_buf.extend_from_slice(&self.inbox[4..4 + length]);
+            self.inbox.drain(..4 + length);
+            return Ok(true);
+        }
     }
 
     async fn handle_message(&mut self) -> anyhow::Result<()> {
@@ -116,6 +344,15 @@ impl PeerConnection {
             0 => {
                 self.am_choked = true;
                 // println!("{} choked us", self.peer.addr);
+
+                // a choke discards everything we asked for, unless the peer
+                // rejects each request explicitly under the fast extension
+                if !self.fast_extension {
+                    // blocks sent before the choke have all arrived by now
+                    self.pending.clear();
+                    self.cancelled.clear();
+                    self.piece_manager.lock().await.release_requests(self.peer.addr);
+                }
             }
 
             1 => {
@@ -126,12 +363,16 @@ impl PeerConnection {
 
             2 => {
                 println!("{} is interested", self.peer.addr);
-                todo!("implement seeding");
+                self.peer_interested = true;
+                if self.peer_choked {
+                    self.peer_choked = false;
+                    self.send_unchoke().await?;
+                }
             }
 
             3 => {
                 println!("{} is not interested", self.peer.addr);
-                todo!("implement seeding");
+                self.peer_interested = false;
             }
 
             4 => {
@@ -145,8 +386,7 @@ impl PeerConnection {
             }
 
             6 => {
-                println!("{} is requesting a piece", self.peer.addr);
-                todo!("implement seeding");
+                self.handle_request().await?;
             }
 
             7 => {
@@ -154,13 +394,29 @@ impl PeerConnection {
             }
 
             8 => {
-                println!("{} sent cancel", self.peer.addr);
+                // requests are answered as soon as they arrive, so nothing is queued to cancel
             }
 
             9 => {
                 println!("{} sent port", self.peer.addr);
             }
 
+            13 | 17 => {
+                // suggest piece / allowed fast: advisory only
+            }
+
+            14 => {
+                self.handle_have_all(true).await?;
+            }
+
+            15 => {
+                self.handle_have_all(false).await?;
+            }
+
+            16 => {
+                self.handle_reject().await?;
+            }
+
             _ => {
                 print!("unknown message id: {}", self.msg_buf[0]);
             }
@@ -168,6 +424,70 @@ impl PeerConnection {
         Ok(())
     }
 
+    async fn handle_event(&mut self, event: PieceEvent) -> anyhow::Result<()> {
+        match event {
+            PieceEvent::Cancel { piece_index, begin, length, peers } => {
+                if peers.contains(&self.peer.addr) && self.pending.remove(&(piece_index, begin)) {
+                    self.cancelled.insert((piece_index, begin));
+                    self.send_cancel(piece_index, begin, length).await?;
+                    self.maybe_request_next().await?;
+                }
+            }
+
+            PieceEvent::Verified(piece_index) => {
+                // a resync may have announced it already
+                if !self.announced[piece_index] {
+                    self.send_have(piece_index).await?;
+                }
+                self.update_interest().await?;
+            }
+
+            PieceEvent::Written(piece_index) => {
+                // late answers to cancelled requests for it are recognised by `has_piece` from now on
+                self.cancelled.retain(|&(p, _)| p != piece_index);
+                self.maybe_request_next().await?;
+            }
+
+            PieceEvent::Resumed | PieceEvent::BuffersFreed => {
+                // block memory freed up or we were unpaused; pick up where we stopped
+                self.maybe_request_next().await?;
+            }
+
+            PieceEvent::Banned(ip) => {
+                if ip == self.peer.addr.ip() {
+                    anyhow::bail!("{} is banned", self.peer.addr);
+                }
+            }
+
+            PieceEvent::Paused | PieceEvent::Moved(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Catches up after falling behind on events, which were lost rather than
+    /// queued. Everything they would have told us is read from the piece manager.
+    async fn resync(&mut self) -> anyhow::Result<()> {
+        let (bitfield, banned) = {
+            let pm = self.piece_manager.lock().await;
+            (pm.compact_bitfield(), pm.is_banned(self.peer.addr.ip()))
+        };
+        if banned {
+            anyhow::bail!("{} is banned", self.peer.addr);
+        }
+
+        let have = |piece_index: usize| bitfield[piece_index / 8] & (1 << (7 - piece_index % 8)) != 0;
+        for piece_index in 0..self.announced.len() {
+            if have(piece_index) && !self.announced[piece_index] {
+                self.send_have(piece_index).await?;
+            }
+        }
+        // the `Written` events that would have cleared these were among the lost ones
+        self.cancelled.retain(|&(p, _)| !have(p));
+        self.update_interest().await?;
+        self.maybe_request_next().await?;
+        Ok(())
+    }
+
     async fn handle_have(&mut self) -> anyhow::Result<()> {
         if self.msg_buf.len() < 5 { return Ok (()); }
 
@@ -176,8 +496,17 @@ impl PeerConnection {
 
         let piece_index = u32::from_be_bytes(piece_index_network_order) as usize;
         // println!("{} has piece: {}", self.peer.addr, piece_index);
+        if piece_index >= self.bitfield.len() {
+            anyhow::bail!("{} announced piece {} out of range", self.peer.addr, piece_index);
+        }
 
-        self.bitfield[piece_index] = true;
+        if !self.bitfield[piece_index] {
+            self.bitfield[piece_index] = true;
+            let mut pm = self.piece_manager.lock().await;
+            pm.add_peer_have(piece_index);
+            self.peer.left = self.peer.left.saturating_sub(pm.piece_length_of_index(piece_index) as u64);
+        }
+        self.update_interest().await?;
 
         self.maybe_request_next().await?;
         Ok(())
@@ -186,34 +515,58 @@ impl PeerConnection {
     async fn handle_piece(&mut self) -> anyhow::Result<()> {
         if self.msg_buf.len() < 9 { return Ok(()) }
 
-        self.in_flight -= 1;
         let piece_index = u32::from_be_bytes(self.msg_buf[1..5].try_into()?) as usize;
         let begin = u32::from_be_bytes(self.msg_buf[5..9].try_into()?) as usize;
         let block_data = &self.msg_buf[9..];
 
         // println!("Received piece {piece_index}, begin: {begin}, length: {}", block_data.len());
-        let _ = {
+        let requested = self.pending.remove(&(piece_index, begin)) || self.cancelled.remove(&(piece_index, begin));
+        let result = {
             let mut pm = self.piece_manager.lock().await;
-            pm.add_block(piece_index, begin, block_data)?;
+            let result = if requested {
+                pm.add_block(self.peer.addr, piece_index, begin, block_data)
+            } else if pm.has_piece(piece_index) {
+                // most likely a request we cancelled in endgame, answered after the piece was done
+                return Ok(());
+            } else {
+                Err(BlockError::Unrequested { piece_index, begin })
+            };
+            if result.is_err() {
+                pm.penalise(self.peer.addr.ip());
+            }
+            result
         };
+        if let Err(e) = result {
+            anyhow::bail!("{} sent a bad block: {}", self.peer.addr, e);
+        }
 
-        // self.maybe_request_next().await?;
+        self.maybe_request_next().await?;
         Ok(())
     }
 
     async fn handle_bitfield(&mut self) -> anyhow::Result<()> {
+        let bitfield = parse_bitfield(&self.msg_buf[1..], self.bitfield.len())
+            .map_err(|e| anyhow::anyhow!("{} sent a bad bitfield: {}", self.peer.addr, e))?;
+        self.set_peer_bitfield(bitfield).await
+    }
+
+    /// Fast extension have-all / have-none.
+    async fn handle_have_all(&mut self, has_all: bool) -> anyhow::Result<()> {
+        if !self.fast_extension {
+            anyhow::bail!("{} sent have-all/have-none without the fast extension", self.peer.addr);
+        }
+        let bitfield = vec![has_all; self.bitfield.len()];
+        self.set_peer_bitfield(bitfield).await
+    }
+
+    async fn set_peer_bitfield(&mut self, bitfield: Vec<bool>) -> anyhow::Result<()> {
         // Scoped lock
         let has_piece_we_need = {
-            let pm = self.piece_manager.lock().await;
-
-            // set bitfield
-            for (i, &byte) in self.msg_buf[1..].iter().enumerate() {
-                for bit in 0..8 {
-                    let piece_index = i * 8 + bit;
-                    if piece_index >= self.bitfield.len() { break; }
-                    self.bitfield[piece_index] = (byte & (1 << (7 - bit))) != 0;
-                }
-            } 
+            let mut pm = self.piece_manager.lock().await;
+            pm.remove_peer_bitfield(&self.bitfield);
+            self.bitfield = bitfield;
+            pm.add_peer_bitfield(&self.bitfield);
+            self.peer.left = pm.bytes_missing(&self.bitfield);
 
             // check if peer has any piece we need
             !self.am_interested && pm.peer_has_piece_we_dont(&self.bitfield)
@@ -228,23 +581,145 @@ impl PeerConnection {
         Ok(())
     }
 
+    async fn handle_request(&mut self) -> anyhow::Result<()> {
+        if self.msg_buf.len() < 13 { return Ok(()) }
+
+        let piece_index = u32::from_be_bytes(self.msg_buf[1..5].try_into()?) as usize;
+        let begin = u32::from_be_bytes(self.msg_buf[5..9].try_into()?) as usize;
+        let length = u32::from_be_bytes(self.msg_buf[9..13].try_into()?) as usize;
+
+        if length > MAX_REQUEST_LENGTH {
+            anyhow::bail!("{} requested {} bytes at once", self.peer.addr, length);
+        }
+
+        let has_piece = self.piece_manager.lock().await.has_piece(piece_index);
+
+        if self.peer_choked || !self.peer_interested || !has_piece {
+            if self.fast_extension {
+                self.send_block_message(16, piece_index, begin, length).await?;
+            }
+            return Ok(());
+        }
+
+        let block = match self.disk.read(piece_index, begin, length).await {
+            std::result::Result::Ok(block) => block,
+            Err(e) if e.is::<ReadQueueFull>() => {
+                // our disk is behind; the peer can ask again later
+                if self.fast_extension {
+                    self.send_block_message(16, piece_index, begin, length).await?;
+                }
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        self.send_piece(piece_index, begin, &block).await?;
+        self.piece_manager.lock().await.uploaded += block.len() as u64;
+        Ok(())
+    }
+
+    /// Fast extension reject: the peer will not serve this request.
+    async fn handle_reject(&mut self) -> anyhow::Result<()> {
+        if self.msg_buf.len() < 13 { return Ok(()) }
+
+        let piece_index = u32::from_be_bytes(self.msg_buf[1..5].try_into()?) as usize;
+        let begin = u32::from_be_bytes(self.msg_buf[5..9].try_into()?) as usize;
+
+        self.cancelled.remove(&(piece_index, begin));
+        if self.pending.remove(&(piece_index, begin)) {
+            self.piece_manager.lock().await.release_block(self.peer.addr, piece_index, begin);
+        }
+        Ok(())
+    }
+
+    /// Sends what we already have, right after the handshake.
+    async fn send_bitfield(&mut self) -> anyhow::Result<()> {
+        let (bitfield, num_complete) = {
+            let pm = self.piece_manager.lock().await;
+            (pm.compact_bitfield(), pm.num_complete())
+        };
+
+        for (index, announced) in self.announced.iter_mut().enumerate() {
+            *announced = bitfield[index / 8] & (1 << (7 - index % 8)) != 0;
+        }
+
+        let mut buf = Vec::with_capacity(5 + bitfield.len());
+        if self.fast_extension && num_complete == self.bitfield.len() {
+            buf.extend_from_slice(&1u32.to_be_bytes());
+            buf.push(14); // message ID = have all
+        } else if self.fast_extension && num_complete == 0 {
+            buf.extend_from_slice(&1u32.to_be_bytes());
+            buf.push(15); // message ID = have none
+        } else if num_complete > 0 {
+            buf.extend_from_slice(&(1 + bitfield.len() as u32).to_be_bytes());
+            buf.push(5); // message ID = bitfield
+            buf.extend_from_slice(&bitfield);
+        } else {
+            // without the fast extension an empty bitfield may simply be left out
+            return Ok(());
+        }
+
+        self.send(&buf).await?;
+        Ok(())
+    }
+
+    /// Tells the peer whether it still has anything we need, if that changed.
+    async fn update_interest(&mut self) -> anyhow::Result<()> {
+        let interesting = self.piece_manager.lock().await.peer_has_piece_we_dont(&self.bitfield);
+
+        if interesting && !self.am_interested {
+            self.am_interested = true;
+            self.send_interested().await?;
+        } else if !interesting && self.am_interested {
+            self.am_interested = false;
+            self.send_not_interested().await?;
+        }
+        Ok(())
+    }
+
     async fn send_interested(&mut self) -> anyhow::Result<()> {
         let mut buf = [0u8; 5];
         buf[..4].copy_from_slice(&1u32.to_be_bytes());
         buf[4] = 2; // message ID = interested
-        self.stream.write_all(&buf).await?;
+        self.send(&buf).await?;
+        Ok(())
+    }
+
+    async fn send_unchoke(&mut self) -> anyhow::Result<()> {
+        let mut buf = [0u8; 5];
+        buf[..4].copy_from_slice(&1u32.to_be_bytes());
+        buf[4] = 1; // message ID = unchoke
+        self.send(&buf).await?;
+        Ok(())
+    }
+
+    async fn send_not_interested(&mut self) -> anyhow::Result<()> {
+        let mut buf = [0u8; 5];
+        buf[..4].copy_from_slice(&1u32.to_be_bytes());
+        buf[4] = 3; // message ID = not interested
+        self.send(&buf).await?;
+        Ok(())
+    }
+
+    async fn send_have(&mut self, piece_index: usize) -> anyhow::Result<()> {
+        let mut buf = [0u8; 9];
+        buf[..4].copy_from_slice(&5u32.to_be_bytes());
+        buf[4] = 4; // message ID = have
+        buf[5..].copy_from_slice(&(piece_index as u32).to_be_bytes());
+        self.send(&buf).await?;
+        self.announced[piece_index] = true;
         Ok(())
     }
 
     async fn maybe_request_next(&mut self) -> anyhow::Result<()> {
        loop {
-            if self.am_choked {
+            // the piece manager caps block memory across all peers
+            if self.am_choked || self.pending.len() >= MAX_OUTGOING as usize {
                return Ok(());
             }
 
             let next_block = {
                 let mut pm = self.piece_manager.lock().await;
-                pm.next_block(&self.bitfield)?
+                pm.next_block(self.peer.addr, &self.bitfield)?
             };
 
             let (piece_index, begin, curr_len) = match next_block {
@@ -255,23 +730,110 @@ impl PeerConnection {
             let req_len = (curr_len - begin).min(BLOCK_SIZE);
 
             self.send_request(piece_index, begin, req_len).await?;
-            // self.in_flight += 1;
+            self.pending.insert((piece_index, begin));
         }
 
     }
 
     async fn send_request(&mut self, piece_index: usize, begin: usize, length: usize) -> anyhow::Result<()> {
+        self.send_block_message(6, piece_index, begin, length).await
+    }
+
+    async fn send_cancel(&mut self, piece_index: usize, begin: usize, length: usize) -> anyhow::Result<()> {
+        self.send_block_message(8, piece_index, begin, length).await
+    }
+
+    async fn send_piece(&mut self, piece_index: usize, begin: usize, block: &[u8]) -> anyhow::Result<()> {
+        let mut buf = Vec::with_capacity(13 + block.len());
+        buf.extend_from_slice(&(9 + block.len() as u32).to_be_bytes());
+        buf.push(7); // message ID = piece
+        buf.extend_from_slice(&(piece_index as u32).to_be_bytes());
+        buf.extend_from_slice(&(begin as u32).to_be_bytes());
+        buf.extend_from_slice(block);
+
+        self.send_message(&buf, block.len()).await?;
+        self.peer.uploaded += block.len() as u64;
+        Ok(())
+    }
+
+    async fn send_block_message(&mut self, id: u8, piece_index: usize, begin: usize, length: usize) -> anyhow::Result<()> {
 
         let mut buf = Vec::with_capacity(17);
         buf.extend_from_slice(&(13u32.to_be_bytes()));   // length prefix
-        buf.push(6u8);                                   // message ID (request)
+        buf.push(id);                                    // message ID (request / cancel / reject)
         buf.extend_from_slice(&(piece_index as u32).to_be_bytes()); 
         buf.extend_from_slice(&(begin as u32).to_be_bytes()); 
         buf.extend_from_slice(&(length as u32).to_be_bytes()); 
         
-        self.stream.write_all(&buf).await?;
+        self.send(&buf).await?;
         Ok(())
     }
 
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bitfields_must_match_the_torrent() {
+        assert_eq!(parse_bitfield(&[0b1010_0000], 3).unwrap(), [true, false, true]);
+        assert_eq!(parse_bitfield(&[0xff, 0x80], 9).unwrap(), [true; 9]);
+        assert_eq!(parse_bitfield(&[0x01], 8).unwrap(), [false, false, false, false, false, false, false, true]);
+
+        // one byte too many or too few
+        assert!(parse_bitfield(&[0xff, 0x00], 8).is_err());
+        assert!(parse_bitfield(&[0xff], 9).is_err());
+        // a bit for a tenth piece that does not exist
+        assert!(parse_bitfield(&[0xff, 0xc0], 9).is_err());
+        assert!(parse_bitfield(&[0x01], 7).is_err());
+    }
+
+    fn piece_message(piece_index: u32, block: &[u8]) -> Vec<u8> {
+        let mut msg = Vec::new();
+        msg.extend_from_slice(&(9 + block.len() as u32).to_be_bytes());
+        msg.push(7);
+        msg.extend_from_slice(&piece_index.to_be_bytes());
+        msg.extend_from_slice(&0u32.to_be_bytes());
+        msg.extend_from_slice(block);
+        msg
+    }
+
+    #[tokio::test]
+    async fn unrequested_blocks_drop_the_connection() {
+        use crate::pieces::picker::RarestFirstPicker;
+        use crate::pieces::storage::MemoryStorage;
+        use sha1::Digest;
+
+        // storage starts zeroed, so only piece 0 checks out as already done
+        let mut hashes = sha1::Sha1::digest([0; BLOCK_SIZE]).to_vec();
+        hashes.extend_from_slice(&[0; 20]);
+        let mut pm = PieceManager::new(BLOCK_SIZE, 2 * BLOCK_SIZE, &hashes, Arc::new(MemoryStorage::new(BLOCK_SIZE, 2 * BLOCK_SIZE)), Box::new(RarestFirstPicker));
+        pm.check_pieces(|_| {});
+        let pm = Arc::new(Mutex::new(pm));
+        let info_hash = Arc::new(vec![7; 20]);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let conn = PeerConnection::new(addr, info_hash.clone(), generate_peer_id(), pm.clone()).await.unwrap();
+        let (mut remote, _) = listener.accept().await.unwrap();
+        let session = tokio::spawn(conn.start());
+
+        let mut handshake = [0u8; 68];
+        remote.read_exact(&mut handshake).await.unwrap();
+        handshake[48..68].copy_from_slice(&generate_peer_id());
+        remote.write_all(&handshake).await.unwrap();
+
+        // a late answer for a piece we already have is let through quietly
+        remote.write_all(&piece_message(0, &[0; BLOCK_SIZE])).await.unwrap();
+        // a block for piece 1 that nobody asked for is not
+        remote.write_all(&piece_message(1, &[0; BLOCK_SIZE])).await.unwrap();
+
+        let err = session.await.unwrap().unwrap_err().to_string();
+        assert!(err.contains("unrequested block at 0 in piece 1"), "{err}");
+        // nothing was taken, and a single strike is not a ban yet
+        let pm = pm.lock().await;
+        assert_eq!(pm.bytes_left(), BLOCK_SIZE as u64);
+        assert!(!pm.is_banned(addr.ip()));
+    }
+}
@@ -0,0 +1,333 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use tokio::sync::{Mutex, Semaphore};
+
+use crate::peers::ip_filter::IpFilter;
+use crate::peers::peer_connection::{self, DEFAULT_IDLE_TIMEOUT};
+use crate::peers::rate_limit::{ConnectionLimits, Rate, RateLimiter, SessionLimits};
+use crate::peers::registry::{ConnectionError, PeerRegistry};
+use crate::peers::PeerConnection;
+use crate::pieces::piece_manager::PieceManager;
+use crate::stats::{self, ConnectionStats, PeerStats, TorrentStats, TransferStats};
+
+/// Connections across all torrents unless configured otherwise.
+pub const DEFAULT_GLOBAL_CONNECTIONS: usize = 200;
+pub const DEFAULT_TORRENT_CONNECTIONS: usize = 50;
+
+// candidates kept per torrent; tracker answers beyond this are ignored
+const MAX_CANDIDATES: usize = 1000;
+// failed attempts in a row before an address is dropped
+const MAX_FAILURES: u32 = 5;
+const BASE_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_BACKOFF: Duration = Duration::from_secs(30 * 60);
+// a connection that lasted this long was worth having; start its backoff over
+const USEFUL_CONNECTION: Duration = Duration::from_secs(60);
+const FILL_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Candidate {
+    failures: u32,
+    next_attempt: Instant,
+    connected: bool,
+    // per-peer limiter and counters of the live connection
+    limiter: Option<Arc<RateLimiter>>,
+    stats: Option<Arc<TransferStats>>,
+}
+
+/// Decides which peers of one torrent to connect to.
+///
+/// Addresses from trackers become candidates. Up to the per-torrent limit are
+/// connected at a time, each holding a permit from the session-wide semaphore.
+/// Failed addresses back off exponentially and are dropped after a few tries.
+pub struct PeerManager {
+    info_hash: Arc<Vec<u8>>,
+    // ours, for this session
+    peer_id: [u8; 20],
+    piece_manager: Arc<Mutex<PieceManager>>,
+    global_slots: Arc<Semaphore>,
+    max_connections: usize,
+    session_limits: Arc<SessionLimits>,
+    torrent_limits: Arc<RateLimiter>,
+    session_stats: Arc<TransferStats>,
+    torrent_stats: Arc<TransferStats>,
+    // limits given to every peer connection
+    peer_upload: AtomicU64,
+    peer_download: AtomicU64,
+    ip_filter: RwLock<Arc<IpFilter>>,
+    // applies to connections made from now on
+    idle_timeout_ms: AtomicU64,
+    candidates: std::sync::Mutex<HashMap<SocketAddr, Candidate>>,
+    // peer IDs of open connections, so each peer is connected once
+    registry: Arc<PeerRegistry>,
+    // addresses that turned out to be ourselves
+    own_addrs: std::sync::Mutex<HashSet<SocketAddr>>,
+}
+
+impl PeerManager {
+    pub fn new(
+        info_hash: Arc<Vec<u8>>,
+        piece_manager: Arc<Mutex<PieceManager>>,
+        global_slots: Arc<Semaphore>,
+        max_connections: usize,
+        session_limits: Arc<SessionLimits>,
+        session_stats: Arc<TransferStats>,
+    ) -> Arc<Self> {
+        let peer_id = peer_connection::generate_peer_id();
+        Arc::new(Self {
+            info_hash,
+            peer_id,
+            piece_manager,
+            global_slots,
+            max_connections,
+            session_limits,
+            torrent_limits: RateLimiter::unlimited(),
+            session_stats,
+            torrent_stats: TransferStats::new(),
+            peer_upload: AtomicU64::new(0),
+            peer_download: AtomicU64::new(0),
+            ip_filter: RwLock::new(Arc::new(IpFilter::default())),
+            idle_timeout_ms: AtomicU64::new(DEFAULT_IDLE_TIMEOUT.as_millis() as u64),
+            candidates: std::sync::Mutex::new(HashMap::new()),
+            registry: PeerRegistry::new(peer_id),
+            own_addrs: std::sync::Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Adds addresses we have not heard of yet. Known ones keep their backoff.
+    /// Every peer source goes through here, so filtered addresses never get this far.
+    pub fn add_candidates(&self, addrs: impl IntoIterator<Item = SocketAddr>) {
+        let filter = self.ip_filter.read().unwrap().clone();
+        let own_addrs = self.own_addrs.lock().unwrap();
+        let mut candidates = self.candidates.lock().unwrap();
+        let now = Instant::now();
+        for addr in addrs.into_iter().filter(|a| !filter.is_blocked(a.ip()) && !own_addrs.contains(a)) {
+            if candidates.len() >= MAX_CANDIDATES { break; }
+            candidates.entry(addr).or_insert(Candidate { failures: 0, next_attempt: now, connected: false, limiter: None, stats: None });
+        }
+    }
+
+    /// Replaces the filter and forgets candidates it blocks. Open connections
+    /// are left alone.
+    pub fn set_ip_filter(&self, filter: Arc<IpFilter>) {
+        self.candidates.lock().unwrap().retain(|addr, c| c.connected || !filter.is_blocked(addr.ip()));
+        *self.ip_filter.write().unwrap() = filter;
+    }
+
+    /// Whether a connection from `addr` may be accepted.
+    pub fn is_allowed(&self, addr: &SocketAddr) -> bool {
+        !self.ip_filter.read().unwrap().is_blocked(addr.ip())
+    }
+
+    /// How long a connection may go without inbound traffic before it is dropped.
+    pub fn set_idle_timeout(&self, timeout: Duration) {
+        self.idle_timeout_ms.store(timeout.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Limits for this torrent as a whole; LAN peers are not counted against them.
+    pub fn torrent_limits(&self) -> &Arc<RateLimiter> {
+        &self.torrent_limits
+    }
+
+    /// Limits each connection of this torrent on its own, including open ones.
+    pub fn set_peer_limits(&self, upload: Rate, download: Rate) {
+        self.peer_upload.store(upload, Ordering::Relaxed);
+        self.peer_download.store(download, Ordering::Relaxed);
+        for limiter in self.candidates.lock().unwrap().values().filter_map(|c| c.limiter.as_ref()) {
+            limiter.upload.set_rate(upload);
+            limiter.download.set_rate(download);
+        }
+    }
+
+    /// Totals, rates and ETA for the torrent, plus a line per connected peer.
+    pub async fn stats(&self) -> TorrentStats {
+        let transfer = self.torrent_stats.snapshot();
+        let left = self.piece_manager.lock().await.bytes_left();
+        let peers = self
+            .candidates
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(addr, c)| Some(PeerStats { addr: *addr, transfer: c.stats.as_ref()?.snapshot() }))
+            .collect();
+
+        TorrentStats { transfer, left, eta: stats::eta(left, transfer.download_rate), peers }
+    }
+
+    pub fn num_connected(&self) -> usize {
+        self.candidates.lock().unwrap().values().filter(|c| c.connected).count()
+    }
+
+    pub fn num_candidates(&self) -> usize {
+        self.candidates.lock().unwrap().len()
+    }
+
+    /// Keeps the torrent topped up with connections for as long as the runtime lives.
+    pub fn spawn(self: &Arc<Self>) {
+        let manager = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(FILL_INTERVAL);
+            loop {
+                interval.tick().await;
+                manager.fill().await;
+            }
+        });
+    }
+
+    async fn fill(self: &Arc<Self>) {
+        let ready: Vec<SocketAddr> = {
+            let candidates = self.candidates.lock().unwrap();
+            let connected = candidates.values().filter(|c| c.connected).count();
+            let now = Instant::now();
+
+            let mut ready: Vec<(&SocketAddr, &Candidate)> = candidates
+                .iter()
+                .filter(|(_, c)| !c.connected && c.next_attempt <= now)
+                .collect();
+            // addresses that never failed first
+            ready.sort_by_key(|(_, c)| c.failures);
+            ready.into_iter().take(self.max_connections.saturating_sub(connected)).map(|(a, _)| *a).collect()
+        };
+
+        for addr in ready {
+            if !self.is_allowed(&addr) || self.piece_manager.lock().await.is_banned(addr.ip()) {
+                self.candidates.lock().unwrap().remove(&addr);
+                continue;
+            }
+            // other torrents hold the rest of the session's slots
+            let Ok(permit) = self.global_slots.clone().try_acquire_owned() else { return };
+
+            let peer_limiter = Arc::new(RateLimiter::new(
+                self.peer_upload.load(Ordering::Relaxed),
+                self.peer_download.load(Ordering::Relaxed),
+            ));
+            let peer_stats = TransferStats::new();
+            if let Some(c) = self.candidates.lock().unwrap().get_mut(&addr) {
+                c.connected = true;
+                c.limiter = Some(peer_limiter.clone());
+                c.stats = Some(peer_stats.clone());
+            }
+            let counters = ConnectionStats::new(peer_stats, self.torrent_stats.clone(), self.session_stats.clone());
+            let limits = ConnectionLimits::for_peer(&addr, &self.session_limits, &self.torrent_limits, peer_limiter);
+
+            let manager = self.clone();
+            let registry = self.registry.clone();
+            let idle_timeout = Duration::from_millis(self.idle_timeout_ms.load(Ordering::Relaxed));
+            tokio::spawn(async move {
+                let started = Instant::now();
+                let result = match PeerConnection::new(addr, manager.info_hash.clone(), manager.peer_id, manager.piece_manager.clone()).await {
+                    Ok(conn) => {
+                        conn.with_idle_timeout(idle_timeout)
+                            .with_rate_limits(limits)
+                            .with_stats(counters)
+                            .with_registry(registry)
+                            .start()
+                            .await
+                    }
+                    Err(e) => Err(e),
+                };
+                drop(permit);
+
+                let refused = result.as_ref().err().and_then(|e| e.downcast_ref::<ConnectionError>());
+                match refused {
+                    Some(ConnectionError::SelfConnection) => {
+                        println!("Peer {} is ourselves, not connecting again", addr);
+                        manager.own_addrs.lock().unwrap().insert(addr);
+                        manager.candidates.lock().unwrap().remove(&addr);
+                    }
+                    // the peer is reachable through another address
+                    Some(ConnectionError::Duplicate) => {
+                        println!("Peer {} is already connected, dropping duplicate", addr);
+                        manager.candidates.lock().unwrap().remove(&addr);
+                    }
+                    None => {
+                        if let Err(e) = &result {
+                            eprintln!("Peer {} failed: {:?}", addr, e);
+                        }
+                        manager.disconnected(addr, started.elapsed());
+                    }
+                }
+            });
+        }
+    }
+
+    // every connection ends with an error of some sort, so how long it lasted
+    // is what tells a useful peer from a dead one
+    fn disconnected(&self, addr: SocketAddr, lasted: Duration) {
+        let mut candidates = self.candidates.lock().unwrap();
+        let Some(c) = candidates.get_mut(&addr) else { return };
+        c.connected = false;
+        c.limiter = None;
+        c.stats = None;
+
+        c.failures = if lasted >= USEFUL_CONNECTION { 1 } else { c.failures + 1 };
+        if c.failures > MAX_FAILURES {
+            candidates.remove(&addr);
+            return;
+        }
+        let backoff = BASE_BACKOFF.saturating_mul(1 << (c.failures - 1)).min(MAX_BACKOFF);
+        c.next_attempt = Instant::now() + backoff;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pieces::picker::RarestFirstPicker;
+    use crate::pieces::storage::MemoryStorage;
+
+    fn manager() -> Arc<PeerManager> {
+        let pm = PieceManager::new(16384, 16384, &[0; 20], Arc::new(MemoryStorage::new(16384, 16384)), Box::new(RarestFirstPicker));
+        PeerManager::new(
+            Arc::new(vec![0; 20]),
+            Arc::new(Mutex::new(pm)),
+            Arc::new(Semaphore::new(DEFAULT_GLOBAL_CONNECTIONS)),
+            DEFAULT_TORRENT_CONNECTIONS,
+            Arc::new(SessionLimits::default()),
+            TransferStats::new(),
+        )
+    }
+
+    fn backoff(manager: &PeerManager, addr: SocketAddr) -> Option<Duration> {
+        let candidates = manager.candidates.lock().unwrap();
+        Some(candidates.get(&addr)?.next_attempt.saturating_duration_since(Instant::now()))
+    }
+
+    #[test]
+    fn failures_back_off_exponentially_until_the_address_is_dropped() {
+        let manager = manager();
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        manager.add_candidates([addr]);
+
+        let mut expected = BASE_BACKOFF;
+        for _ in 0..MAX_FAILURES {
+            manager.disconnected(addr, Duration::ZERO);
+            let wait = backoff(&manager, addr).unwrap();
+            assert!(wait <= expected && wait > expected - Duration::from_secs(1), "{wait:?} vs {expected:?}");
+            expected = (expected * 2).min(MAX_BACKOFF);
+        }
+
+        manager.disconnected(addr, Duration::ZERO);
+        assert_eq!(backoff(&manager, addr), None);
+        // and adding it again starts from scratch
+        manager.add_candidates([addr]);
+        assert_eq!(backoff(&manager, addr), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn a_useful_connection_resets_the_backoff() {
+        let manager = manager();
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        manager.add_candidates([addr]);
+
+        for _ in 0..3 {
+            manager.disconnected(addr, Duration::ZERO);
+        }
+        assert!(backoff(&manager, addr).unwrap() > 2 * BASE_BACKOFF);
+
+        manager.disconnected(addr, USEFUL_CONNECTION);
+        assert!(backoff(&manager, addr).unwrap() <= BASE_BACKOFF);
+    }
+}
@@ -0,0 +1,202 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Bytes per second; `0` means unlimited.
+pub type Rate = u64;
+
+/// Token bucket holding up to one second's worth of bytes.
+///
+/// Takers may overdraw it; they then wait until the debt is paid off. That
+/// keeps large messages from being starved by small ones.
+pub struct TokenBucket {
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    rate: Rate,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: Rate) -> Self {
+        Self {
+            state: Mutex::new(BucketState { rate, tokens: rate as f64, last_refill: Instant::now() }),
+        }
+    }
+
+    pub fn rate(&self) -> Rate {
+        self.state.lock().unwrap().rate
+    }
+
+    pub fn set_rate(&self, rate: Rate) {
+        let mut state = self.state.lock().unwrap();
+        state.rate = rate;
+        state.tokens = state.tokens.min(rate as f64);
+    }
+
+    /// Takes `bytes` from the bucket and returns how long to wait before they may be used.
+    pub fn take(&self, bytes: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        if state.rate == 0 { return Duration::ZERO; }
+
+        let now = Instant::now();
+        let refill = now.duration_since(state.last_refill).as_secs_f64() * state.rate as f64;
+        state.tokens = (state.tokens + refill).min(state.rate as f64);
+        state.last_refill = now;
+
+        state.tokens -= bytes as f64;
+        if state.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.tokens / state.rate as f64)
+        }
+    }
+}
+
+/// Upload and download limits for one level: the session, a torrent or a peer.
+pub struct RateLimiter {
+    pub upload: TokenBucket,
+    pub download: TokenBucket,
+}
+
+impl RateLimiter {
+    pub fn new(upload: Rate, download: Rate) -> Self {
+        Self { upload: TokenBucket::new(upload), download: TokenBucket::new(download) }
+    }
+
+    pub fn unlimited() -> Arc<Self> {
+        Arc::new(Self::new(0, 0))
+    }
+}
+
+/// Peers are limited by the session limiter of their class.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum PeerClass {
+    Internet,
+    /// Private, loopback and link-local addresses. They skip torrent limits.
+    Local,
+}
+
+impl PeerClass {
+    pub fn of(addr: &SocketAddr) -> Self {
+        let local = match addr.ip() {
+            IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local(),
+            IpAddr::V6(ip) => {
+                // fc00::/7 unique local, fe80::/10 link local
+                ip.is_loopback() || (ip.segments()[0] & 0xfe00) == 0xfc00 || (ip.segments()[0] & 0xffc0) == 0xfe80
+            }
+        };
+        if local { PeerClass::Local } else { PeerClass::Internet }
+    }
+}
+
+/// Session-wide limiters, one per peer class. LAN peers are unlimited by default.
+pub struct SessionLimits {
+    pub internet: Arc<RateLimiter>,
+    pub local: Arc<RateLimiter>,
+}
+
+impl SessionLimits {
+    pub fn new(upload: Rate, download: Rate) -> Self {
+        Self { internet: Arc::new(RateLimiter::new(upload, download)), local: RateLimiter::unlimited() }
+    }
+
+    pub fn class(&self, class: PeerClass) -> &Arc<RateLimiter> {
+        match class {
+            PeerClass::Internet => &self.internet,
+            PeerClass::Local => &self.local,
+        }
+    }
+}
+
+impl Default for SessionLimits {
+    fn default() -> Self {
+        Self::new(0, 0)
+    }
+}
+
+/// Every limiter one connection's traffic counts against.
+#[derive(Clone, Default)]
+pub struct ConnectionLimits {
+    limiters: Vec<Arc<RateLimiter>>,
+}
+
+impl ConnectionLimits {
+    /// Session (by class), torrent and peer limiters for a peer at `addr`.
+    pub fn for_peer(addr: &SocketAddr, session: &SessionLimits, torrent: &Arc<RateLimiter>, peer: Arc<RateLimiter>) -> Self {
+        let class = PeerClass::of(addr);
+        let mut limiters = vec![session.class(class).clone()];
+        if class == PeerClass::Internet {
+            limiters.push(torrent.clone());
+        }
+        limiters.push(peer);
+        Self { limiters }
+    }
+
+    pub async fn upload(&self, bytes: usize) {
+        let wait = self.limiters.iter().map(|l| l.upload.take(bytes)).max().unwrap_or_default();
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    pub async fn download(&self, bytes: usize) {
+        let wait = self.limiters.iter().map(|l| l.download.take(bytes)).max().unwrap_or_default();
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_starts_full_and_charges_overdraft_as_wait() {
+        let bucket = TokenBucket::new(1000);
+        assert_eq!(bucket.take(1000), Duration::ZERO);
+
+        let wait = bucket.take(500);
+        assert!(wait > Duration::from_millis(400) && wait <= Duration::from_millis(500), "{wait:?}");
+
+        // a lower rate cannot leave more than a second's worth behind
+        let bucket = TokenBucket::new(1000);
+        bucket.set_rate(100);
+        assert!(bucket.take(200) > Duration::from_millis(900));
+
+        assert_eq!(TokenBucket::new(0).take(usize::MAX), Duration::ZERO);
+    }
+
+    #[test]
+    fn private_and_link_local_addresses_are_local() {
+        for addr in ["192.168.1.2:1", "10.0.0.1:1", "127.0.0.1:1", "169.254.0.1:1", "[fe80::1]:1", "[fd00::1]:1", "[::1]:1"] {
+            assert_eq!(PeerClass::of(&addr.parse().unwrap()), PeerClass::Local, "{addr}");
+        }
+        for addr in ["8.8.8.8:1", "[2001:db8::1]:1"] {
+            assert_eq!(PeerClass::of(&addr.parse().unwrap()), PeerClass::Internet, "{addr}");
+        }
+    }
+
+    #[tokio::test]
+    async fn local_peers_skip_the_torrent_limit() {
+        let session = SessionLimits::new(1000, 1000);
+        let torrent = Arc::new(RateLimiter::new(1000, 1000));
+
+        let local = ConnectionLimits::for_peer(&"192.168.1.2:1".parse().unwrap(), &session, &torrent, RateLimiter::unlimited());
+        local.upload(1000).await;
+        // neither bucket was touched, both still hold a full second
+        assert_eq!(torrent.upload.take(1000), Duration::ZERO);
+        assert_eq!(session.internet.upload.take(1000), Duration::ZERO);
+
+        let torrent = Arc::new(RateLimiter::new(1000, 1000));
+        let session = SessionLimits::new(1000, 1000);
+
+        let remote = ConnectionLimits::for_peer(&"8.8.8.8:1".parse().unwrap(), &session, &torrent, RateLimiter::unlimited());
+        remote.upload(1000).await;
+        assert!(torrent.upload.take(500) > Duration::ZERO);
+        assert!(session.internet.upload.take(500) > Duration::ZERO);
+    }
+}
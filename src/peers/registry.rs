@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Notify;
+
+/// Why a connection was refused after the handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionError {
+    /// The remote peer ID is ours: the address loops back to this client.
+    SelfConnection,
+    /// Another connection reaches the same peer ID and is the one kept.
+    Duplicate,
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionError::SelfConnection => write!(f, "connected to ourselves"),
+            ConnectionError::Duplicate => write!(f, "already connected to this peer"),
+        }
+    }
+}
+
+impl std::error::Error for ConnectionError {}
+
+struct Entry {
+    id: u64,
+    outgoing: bool,
+    close: Arc<Notify>,
+}
+
+/// Peer IDs of one torrent's live connections, so the same peer is only
+/// connected once.
+///
+/// When two connections turn out to reach the same peer, both ends must drop
+/// the same one, so the choice only depends on what both ends know: the two
+/// peer IDs and who opened which connection. If they were opened in opposite
+/// directions, the one opened by the side with the lower peer ID stays. If
+/// one side opened both, the first stays and the second is refused, which
+/// is also the order the other side registers them in.
+pub struct PeerRegistry {
+    our_id: [u8; 20],
+    next_id: AtomicU64,
+    peers: Mutex<HashMap<[u8; 20], Entry>>,
+}
+
+/// A connection's place in the registry, given up when dropped. `close` is
+/// notified if a later connection to the same peer wins.
+pub struct Registration {
+    registry: Arc<PeerRegistry>,
+    peer_id: [u8; 20],
+    id: u64,
+    pub close: Arc<Notify>,
+}
+
+impl PeerRegistry {
+    pub fn new(our_id: [u8; 20]) -> Arc<Self> {
+        Arc::new(Self { our_id, next_id: AtomicU64::new(0), peers: Mutex::new(HashMap::new()) })
+    }
+
+    pub fn register(self: &Arc<Self>, peer_id: [u8; 20], outgoing: bool) -> Result<Registration, ConnectionError> {
+        if peer_id == self.our_id {
+            return Err(ConnectionError::SelfConnection);
+        }
+
+        let mut peers = self.peers.lock().unwrap();
+        if let Some(existing) = peers.get(&peer_id) {
+            // the lower ID's connection is the one it opened
+            let keep_new = existing.outgoing != outgoing && outgoing == (self.our_id < peer_id);
+            if !keep_new {
+                return Err(ConnectionError::Duplicate);
+            }
+            existing.close.notify_one();
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let close = Arc::new(Notify::new());
+        peers.insert(peer_id, Entry { id, outgoing, close: close.clone() });
+        Ok(Registration { registry: self.clone(), peer_id, id, close })
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut peers = self.registry.peers.lock().unwrap();
+        // the entry may already belong to the connection that replaced us
+        if peers.get(&self.peer_id).is_some_and(|e| e.id == self.id) {
+            peers.remove(&self.peer_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    #[test]
+    fn refuses_our_own_id() {
+        let registry = PeerRegistry::new([5; 20]);
+        assert_eq!(registry.register([5; 20], true).err(), Some(ConnectionError::SelfConnection));
+    }
+
+    #[test]
+    fn same_direction_keeps_the_first_connection() {
+        let registry = PeerRegistry::new([5; 20]);
+        let first = registry.register([9; 20], true).unwrap();
+        assert_eq!(registry.register([9; 20], true).err(), Some(ConnectionError::Duplicate));
+        assert!(first.close.notified().now_or_never().is_none());
+
+        drop(first);
+        assert!(registry.register([9; 20], true).is_ok());
+    }
+
+    #[test]
+    fn opposite_directions_keep_the_lower_ids_connection() {
+        // we have the lower ID, so our outgoing connection stays
+        let registry = PeerRegistry::new([1; 20]);
+        let _outgoing = registry.register([9; 20], true).unwrap();
+        assert!(registry.register([9; 20], false).is_err());
+
+        // they have the lower ID, so their connection to us wins
+        let registry = PeerRegistry::new([9; 20]);
+        let outgoing = registry.register([1; 20], true).unwrap();
+        let incoming = registry.register([1; 20], false).unwrap();
+        assert!(outgoing.close.notified().now_or_never().is_some());
+        // the replaced connection going away leaves the winner in place
+        drop(outgoing);
+        assert!(registry.register([1; 20], true).is_err());
+        drop(incoming);
+        assert!(registry.register([1; 20], true).is_ok());
+    }
+
+    #[test]
+    fn both_ends_drop_the_same_connection() {
+        let (a, b) = ([1; 20], [9; 20]);
+        let at_a = PeerRegistry::new(a);
+        let at_b = PeerRegistry::new(b);
+
+        // each opens a connection to the other at the same time
+        let a_to_b_at_a = at_a.register(b, true).unwrap();
+        let b_to_a_at_b = at_b.register(a, true).unwrap();
+        let b_to_a_at_a = at_a.register(b, false);
+        let a_to_b_at_b = at_b.register(a, false);
+
+        // both keep the connection A opened
+        assert!(b_to_a_at_a.is_err());
+        assert!(a_to_b_at_b.is_ok());
+        assert!(a_to_b_at_a.close.notified().now_or_never().is_none());
+        assert!(b_to_a_at_b.close.notified().now_or_never().is_some());
+    }
+}
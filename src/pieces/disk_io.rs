@@ -0,0 +1,228 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use tokio::sync::{mpsc, oneshot};
+
+use crate::pieces::picker::Priority;
+use crate::pieces::storage::Storage;
+
+pub const DEFAULT_DISK_THREADS: usize = 4;
+pub const DEFAULT_WRITE_QUEUE: usize = 64;
+pub const DEFAULT_READ_QUEUE: usize = 256;
+
+/// Finished disk work, reported back to whoever owns the receiver.
+#[derive(Debug)]
+pub enum DiskEvent {
+    Written { piece_index: usize, begin: usize },
+    WriteFailed { piece_index: usize, begin: usize, error: std::io::ErrorKind, message: String },
+}
+
+/// A read turned away because the read queue is full. The overload is ours,
+/// so the request should be rejected rather than the peer dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadQueueFull;
+
+impl std::fmt::Display for ReadQueueFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "disk read queue is full")
+    }
+}
+
+impl std::error::Error for ReadQueueFull {}
+
+struct ReadJob {
+    piece_index: usize,
+    begin: usize,
+    length: usize,
+    reply: oneshot::Sender<anyhow::Result<Vec<u8>>>,
+}
+
+struct WriteJob {
+    piece_index: usize,
+    begin: usize,
+    data: Vec<u8>,
+}
+
+// bookkeeping that may touch whole files: renames, copies between locations
+enum FileTask {
+    PieceComplete(usize),
+    SetPriorities(Vec<Priority>),
+}
+
+struct Queues {
+    reads: VecDeque<ReadJob>,
+    writes: VecDeque<WriteJob>,
+    tasks: VecDeque<FileTask>,
+    shutdown: bool,
+}
+
+struct Shared {
+    storage: Arc<dyn Storage>,
+    queues: Mutex<Queues>,
+    ready: Condvar,
+    read_capacity: usize,
+    write_capacity: usize,
+    events: mpsc::UnboundedSender<DiskEvent>,
+}
+
+// stops the workers once the last `DiskIo` handle is gone
+struct Shutdown(Arc<Shared>);
+
+impl Drop for Shutdown {
+    fn drop(&mut self) {
+        self.0.queues.lock().unwrap().shutdown = true;
+        self.0.ready.notify_all();
+    }
+}
+
+/// Blocking storage calls run on a small pool of OS threads so a slow disk
+/// never stalls the async runtime.
+///
+/// Reads (serving peers) go ahead of writes unless the write queue is full.
+/// Received blocks are never dropped, so the write queue can overshoot its
+/// capacity; the piece manager stops handing out requests while it is full,
+/// and its buffer cap bounds how much can pile up.
+#[derive(Clone)]
+pub struct DiskIo {
+    shared: Arc<Shared>,
+    _shutdown: Arc<Shutdown>,
+}
+
+impl DiskIo {
+    pub fn new(
+        storage: Arc<dyn Storage>,
+        threads: usize,
+        read_capacity: usize,
+        write_capacity: usize,
+    ) -> (Self, mpsc::UnboundedReceiver<DiskEvent>) {
+        let (events, rx) = mpsc::unbounded_channel();
+        let shared = Arc::new(Shared {
+            storage,
+            queues: Mutex::new(Queues { reads: VecDeque::new(), writes: VecDeque::new(), tasks: VecDeque::new(), shutdown: false }),
+            ready: Condvar::new(),
+            read_capacity,
+            write_capacity,
+            events,
+        });
+
+        for i in 0..threads.max(1) {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name(format!("disk-io-{i}"))
+                .spawn(move || worker(shared))
+                .expect("failed to spawn disk thread");
+        }
+
+        let shutdown = Arc::new(Shutdown(shared.clone()));
+        (Self { shared, _shutdown: shutdown }, rx)
+    }
+
+    pub fn storage(&self) -> Arc<dyn Storage> {
+        self.shared.storage.clone()
+    }
+
+    /// Queues a received block for writing; completion arrives as a [`DiskEvent`].
+    pub fn write(&self, piece_index: usize, begin: usize, data: Vec<u8>) {
+        self.shared.queues.lock().unwrap().writes.push_back(WriteJob { piece_index, begin, data });
+        self.shared.ready.notify_one();
+    }
+
+    pub async fn read(&self, piece_index: usize, begin: usize, length: usize) -> anyhow::Result<Vec<u8>> {
+        let (reply, rx) = oneshot::channel();
+        {
+            let mut queues = self.shared.queues.lock().unwrap();
+            if queues.reads.len() >= self.shared.read_capacity {
+                return Err(ReadQueueFull.into());
+            }
+            queues.reads.push_back(ReadJob { piece_index, begin, length, reply });
+        }
+        self.shared.ready.notify_one();
+
+        rx.await?
+    }
+
+    /// Runs `Storage::piece_complete` on the disk threads, since finishing a
+    /// file renames it.
+    pub fn piece_complete(&self, piece_index: usize) {
+        self.push_task(FileTask::PieceComplete(piece_index));
+    }
+
+    /// Runs `Storage::set_file_priorities` on the disk threads, since it moves
+    /// data in and out of the part file. Failures are only logged.
+    pub fn set_file_priorities(&self, priorities: Vec<Priority>) {
+        self.push_task(FileTask::SetPriorities(priorities));
+    }
+
+    fn push_task(&self, task: FileTask) {
+        self.shared.queues.lock().unwrap().tasks.push_back(task);
+        self.shared.ready.notify_one();
+    }
+
+    /// New requests should wait until the disk catches up.
+    pub fn is_write_queue_full(&self) -> bool {
+        self.shared.queues.lock().unwrap().writes.len() >= self.shared.write_capacity
+    }
+}
+
+fn worker(shared: Arc<Shared>) {
+    loop {
+        let job = {
+            let mut queues = shared.queues.lock().unwrap();
+            loop {
+                if queues.shutdown { return; }
+
+                if let Some(task) = queues.tasks.pop_front() {
+                    break Job::Task(task);
+                }
+                // reads first, but never let a backed-up write queue starve
+                let writes_backed_up = queues.writes.len() >= shared.write_capacity;
+                if !writes_backed_up && let Some(read) = queues.reads.pop_front() {
+                    break Job::Read(read);
+                }
+                if let Some(write) = queues.writes.pop_front() {
+                    break Job::Write(write);
+                }
+                if let Some(read) = queues.reads.pop_front() {
+                    break Job::Read(read);
+                }
+                queues = shared.ready.wait(queues).unwrap();
+            }
+        };
+
+        match job {
+            Job::Read(read) => {
+                let result = shared.storage.read_block(read.piece_index, read.begin, read.length);
+                // the peer may have gone away in the meantime
+                let _ = read.reply.send(result);
+            }
+
+            Job::Task(FileTask::PieceComplete(piece_index)) => shared.storage.piece_complete(piece_index),
+
+            Job::Task(FileTask::SetPriorities(priorities)) => {
+                if let Err(e) = shared.storage.set_file_priorities(&priorities) {
+                    eprintln!("Failed to apply file priorities: {:?}", e);
+                }
+            }
+
+            Job::Write(write) => {
+                let event = match shared.storage.write_block(write.piece_index, write.begin, &write.data) {
+                    Ok(()) => DiskEvent::Written { piece_index: write.piece_index, begin: write.begin },
+                    Err(e) => DiskEvent::WriteFailed {
+                        piece_index: write.piece_index,
+                        begin: write.begin,
+                        error: e.downcast_ref::<std::io::Error>().map_or(std::io::ErrorKind::Other, |e| e.kind()),
+                        message: format!("{e:?}"),
+                    },
+                };
+                let _ = shared.events.send(event);
+            }
+        }
+    }
+}
+
+enum Job {
+    Read(ReadJob),
+    Write(WriteJob),
+    Task(FileTask),
+}
@@ -1,9 +1,25 @@
-use std::fs::{self, OpenOptions};
-use std::io::{SeekFrom, Seek, Write};
-use std::path::Path;
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{ErrorKind, Read, SeekFrom, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::UNIX_EPOCH;
+
+use crate::pieces::file_pool::{FilePool, DEFAULT_MAX_OPEN_FILES};
+use crate::pieces::picker::Priority;
+use crate::pieces::resume::FileState;
+use crate::pieces::sanitize::{dedup_paths, sanitize_path};
+use crate::pieces::storage::{free_space, AllocationMode, Storage};
+
+const DEFAULT_READ_CACHE_BYTES: usize = 8 * 1024 * 1024;
+// a cache miss reads this much of the piece around the block, aligned to it
+const READ_AHEAD: usize = 128 * 1024;
+// pool slot of the part file, well clear of any real file index
+const PART_FILE_SLOT: usize = usize::MAX;
+const INCOMPLETE_SUFFIX: &str = ".part";
 
 pub struct OutputFile {
-    pub path: String,
+    pub path: String,   // relative to the storage root
     pub start: usize,   // start offset in the torrent
     pub length: usize,
 }
@@ -11,96 +27,788 @@ pub struct OutputFile {
 pub struct FileManager {
     pub files: Vec<OutputFile>,
     pub total_length: usize,
+    pub piece_length: usize,
+    root: RwLock<PathBuf>,
+    // hidden file holding the parts of boundary pieces that fall in skipped files
+    part_file: String,
+    priorities: RwLock<Vec<Priority>>,
+    read_cache: Mutex<ReadCache>,
+    pool: FilePool,
+    // pieces known to be on disk, so handles of finished files can be closed
+    have: Mutex<Vec<bool>>,
+    // unfinished files carry INCOMPLETE_SUFFIX until their last piece is written
+    incomplete_suffix: bool,
+    finished: RwLock<Vec<bool>>,
 }
 
 impl FileManager {
-    pub fn new(info: &crate::torrent::Info) -> anyhow::Result<Self> {
+    /// Lays out the torrent's files under `save_path`. Nothing is created on
+    /// disk until data for a file is written, so skipped files never appear.
+    pub fn new(info: &crate::torrent::Info, save_path: &Path) -> anyhow::Result<Self> {
         let mut files = Vec::new();
         let mut offset = 0;
+        let name = sanitize_path(std::slice::from_ref(&info.name))?;
 
         if let Some(torrent_files) = &info.files {
-            for f in torrent_files {
-                let path = f.path.join("/"); // join the Vec<String> path
-                let parent = Path::new(&path).parent();
-                if let Some(p) = parent {
-                    fs::create_dir_all(p)?;
-                }
-
-                // Ensure file exists
-                if !Path::new(&path).exists() {
-                    OpenOptions::new()
-                        .write(true)
-                        .create(true)
-                        .truncate(true)
-                        .open(&path)?;
-                }
+            let mut paths = torrent_files
+                .iter()
+                .map(|f| sanitize_path(&f.path))
+                .collect::<anyhow::Result<Vec<String>>>()?;
+            dedup_paths(&mut paths);
 
+            for (f, path) in torrent_files.iter().zip(paths) {
                 files.push(OutputFile {
                     path,
                     start: offset,
-                    length: f.length as usize,
+                    length: f.length,
                 });
 
-                offset += f.length as usize;
+                offset += f.length;
             }
         } else {
             // single-file torrent
-            let path = info.name.clone();
-            if let Some(p) = Path::new(&path).parent() {
-                fs::create_dir_all(p)?;
-            }
-
-            if !Path::new(&path).exists() {
-                OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .truncate(true)
-                    .open(&path)?;
-            }
-
             files.push(OutputFile {
-                path,
+                path: name.clone(),
                 start: 0,
-                length: info.length.unwrap_or(0) as usize,
+                length: info.length.unwrap_or(0),
             });
 
-            offset = info.length.unwrap_or(0) as usize;
+            offset = info.length.unwrap_or(0);
         }
 
+        let num_files = files.len();
         Ok(Self {
             files,
             total_length: offset,
+            piece_length: info.piece_length,
+            root: RwLock::new(save_path.to_path_buf()),
+            part_file: format!(".{name}.parts"),
+            priorities: RwLock::new(vec![Priority::default(); num_files]),
+            read_cache: Mutex::new(ReadCache::new(DEFAULT_READ_CACHE_BYTES)),
+            pool: FilePool::new(DEFAULT_MAX_OPEN_FILES),
+            have: Mutex::new(vec![false; offset.div_ceil(info.piece_length.max(1))]),
+            incomplete_suffix: false,
+            finished: RwLock::new(vec![false; num_files]),
         })
     }
 
-    pub fn write_piece(&self, piece_index: usize, data: &[u8], piece_length: usize) -> anyhow::Result<()> {
-        let mut remaining = data;
-        let mut global_offset = piece_index * piece_length;
+    /// Keeps files under a `.part` name until every piece in them is written,
+    /// so other programs never see a half-downloaded file under its real name.
+    pub fn with_incomplete_suffix(mut self) -> Self {
+        self.incomplete_suffix = true;
+        // a file under its final name without a `.part` next to it was finished in an earlier run
+        let root = self.root.read().unwrap().clone();
+        let finished = self
+            .files
+            .iter()
+            .map(|f| root.join(&f.path).exists() && !root.join(format!("{}{INCOMPLETE_SUFFIX}", f.path)).exists())
+            .collect();
+        self.finished = RwLock::new(finished);
+        self
+    }
+
+    /// Whether any of the torrent's files is under the root, finished or not.
+    pub fn files_exist(&self) -> bool {
+        let root = self.root.read().unwrap();
+        self.files
+            .iter()
+            .any(|f| root.join(&f.path).exists() || root.join(format!("{}{INCOMPLETE_SUFFIX}", f.path)).exists())
+    }
+
+    /// Where file `file_index` currently lives on disk.
+    pub fn full_path(&self, file_index: usize) -> PathBuf {
+        let root = self.root.read().unwrap();
+        root.join(self.disk_name(file_index))
+    }
+
+    /// Name of the file relative to the root, with the incomplete suffix if it still has one.
+    fn disk_name(&self, file_index: usize) -> String {
+        let file_info = &self.files[file_index];
+        if self.incomplete_suffix && !self.finished.read().unwrap()[file_index] {
+            format!("{}{INCOMPLETE_SUFFIX}", file_info.path)
+        } else {
+            file_info.path.clone()
+        }
+    }
+
+    /// Renames a fully written file to its final name.
+    fn finish_file(&self, file_index: usize) -> anyhow::Result<()> {
+        // root before finished, the same order as full_path
+        let root = self.root.read().unwrap();
+        let mut finished = self.finished.write().unwrap();
+        if !self.incomplete_suffix || finished[file_index] {
+            return Ok(());
+        }
+
+        let path = &self.files[file_index].path;
+        let from = root.join(format!("{path}{INCOMPLETE_SUFFIX}"));
+        self.pool.close(file_index);
+        if from.exists() {
+            // same directory, so this is an atomic rename
+            fs::rename(&from, root.join(path))?;
+        }
+        finished[file_index] = true;
+        Ok(())
+    }
+
+    /// Creates a wanted empty file. No data is ever written to it, so
+    /// nothing else would, and it is finished from the start.
+    fn create_empty_file(&self, file_index: usize) -> anyhow::Result<()> {
+        let root = self.root.read().unwrap();
+        let path = root.join(&self.files[file_index].path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::OpenOptions::new().create(true).append(true).open(&path)?;
+        self.finished.write().unwrap()[file_index] = true;
+        Ok(())
+    }
+
+    pub fn part_file_path(&self) -> PathBuf {
+        self.root.read().unwrap().join(&self.part_file)
+    }
+
+    /// Pool slot, path and offset to use for bytes of `file_index`. Skipped
+    /// files are redirected into the part file at their offset in the torrent.
+    fn location(&self, file_index: usize) -> Location {
+        let skipped = self.priorities.read().unwrap()[file_index] == Priority::Skip;
+        self.location_for(file_index, skipped)
+    }
+
+    fn location_for(&self, file_index: usize, skipped: bool) -> Location {
+        let file_info = &self.files[file_index];
+        if skipped {
+            Location { slot: PART_FILE_SLOT, path: self.part_file_path(), base: file_info.start }
+        } else {
+            Location { slot: file_index, path: self.full_path(file_index), base: 0 }
+        }
+    }
+
+    /// Splits `length` bytes at `global_offset` in the torrent into per-file pieces.
+    pub(crate) fn segments(&self, global_offset: usize, length: usize) -> anyhow::Result<Vec<Segment>> {
+        let mut segments = Vec::new();
+        let mut done = 0;
+        let mut file_offset = global_offset;
 
-        for file_info in &self.files {
-            if global_offset >= file_info.length {
-                global_offset -= file_info.length;
+        for (file_index, file_info) in self.files.iter().enumerate() {
+            if file_offset >= file_info.length {
+                file_offset -= file_info.length;
                 continue;
             }
 
-            let write_len = remaining.len().min(file_info.length - global_offset);
+            let len = (length - done).min(file_info.length - file_offset);
+            segments.push(Segment { file_index, file_offset, buf: done..done + len });
+
+            done += len;
+            file_offset = 0;
+
+            if done == length {
+                break;
+            }
+        }
+
+        if done < length {
+            anyhow::bail!("range at {global_offset} runs past the end of the torrent");
+        }
+        Ok(segments)
+    }
 
-            let mut file = OpenOptions::new()
-                .write(true)
-                .open(&file_info.path)?;
+    fn write_segment(&self, segment: &Segment, data: &[u8]) -> anyhow::Result<()> {
+        self.write_at(&self.location(segment.file_index), segment.file_offset, data)
+    }
 
-            file.seek(SeekFrom::Start(global_offset as u64))?;
-            file.write_all(&remaining[..write_len])?;
-            // println!("Piece {piece_index} written");
+    fn read_segment(&self, segment: &Segment, buf: &mut [u8]) -> anyhow::Result<()> {
+        self.read_at(&self.location(segment.file_index), segment.file_offset, buf)
+    }
 
-            remaining = &remaining[write_len..];
-            global_offset = 0;
+    fn write_at(&self, location: &Location, file_offset: usize, data: &[u8]) -> anyhow::Result<()> {
+        let file = self.pool.get(location.slot, &location.path, true)?;
+        let mut file = file.lock().unwrap();
 
-            if remaining.is_empty() {
-                break;
+        file.seek(SeekFrom::Start((location.base + file_offset) as u64))?;
+        file.write_all(data)?;
+        Ok(())
+    }
+
+    fn read_at(&self, location: &Location, file_offset: usize, buf: &mut [u8]) -> anyhow::Result<()> {
+        let path = &location.path;
+        let offset = location.base + file_offset;
+
+        let file = match self.pool.get(location.slot, path, false) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                anyhow::bail!("{} is missing", path.display());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let mut file = file.lock().unwrap();
+        file.seek(SeekFrom::Start(offset as u64))?;
+
+        if let Err(e) = file.read_exact(buf) {
+            if e.kind() == ErrorKind::UnexpectedEof {
+                let actual = file.metadata()?.len();
+                anyhow::bail!("{} is {} bytes, need {}", path.display(), actual, offset + buf.len());
+            }
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    pub fn piece_size(&self, piece_index: usize) -> anyhow::Result<usize> {
+        let start = piece_index * self.piece_length;
+        if start >= self.total_length {
+            anyhow::bail!("piece {piece_index} is past the end of the torrent");
+        }
+        Ok(self.piece_length.min(self.total_length - start))
+    }
+
+    /// `READ_AHEAD` bytes of a piece starting at `chunk * READ_AHEAD`, served
+    /// from the read cache when peers keep asking for neighbouring blocks.
+    fn read_chunk(&self, piece_index: usize, chunk: usize) -> anyhow::Result<Arc<Vec<u8>>> {
+        if let Some(data) = self.read_cache.lock().unwrap().get((piece_index, chunk)) {
+            return Ok(data);
+        }
+
+        let begin = chunk * READ_AHEAD;
+        let length = READ_AHEAD.min(self.piece_size(piece_index)? - begin);
+        let data = Arc::new(self.read_range(piece_index * self.piece_length + begin, length)?);
+        self.read_cache.lock().unwrap().insert((piece_index, chunk), data.clone());
+        Ok(data)
+    }
+
+    /// Pieces that hold at least one byte of `file_info`.
+    pub fn pieces_of_file(&self, file_info: &OutputFile) -> std::ops::Range<usize> {
+        if file_info.length == 0 {
+            return 0..0;
+        }
+        let first = file_info.start / self.piece_length;
+        let last = (file_info.start + file_info.length - 1) / self.piece_length;
+        first..last + 1
+    }
+
+    /// Caps the read cache at `bytes`; 0 turns it off.
+    pub fn set_read_cache_size(&self, bytes: usize) {
+        self.read_cache.lock().unwrap().resize(bytes);
+    }
+
+    /// Reads `length` bytes at `global_offset` in the torrent, walking the same
+    /// file layout as `write_block`.
+    fn read_range(&self, global_offset: usize, length: usize) -> anyhow::Result<Vec<u8>> {
+        let mut data = vec![0u8; length];
+        for segment in self.segments(global_offset, length)? {
+            self.read_segment(&segment, &mut data[segment.buf.clone()])?;
+        }
+        Ok(data)
+    }
+
+    /// Carries the already downloaded bytes of a file across a skip / unskip,
+    /// so they stay readable from wherever `location` now points.
+    fn migrate(&self, file_index: usize, was_skipped: bool) -> anyhow::Result<()> {
+        let file_info = &self.files[file_index];
+        let have: Vec<usize> = {
+            let have = self.have.lock().unwrap();
+            self.pieces_of_file(file_info).filter(|&p| have[p]).collect()
+        };
+
+        let from = self.location_for(file_index, was_skipped);
+        let to = self.location_for(file_index, !was_skipped);
+
+        for piece_index in have {
+            let piece_start = piece_index * self.piece_length;
+            let start = piece_start.max(file_info.start);
+            let end = (piece_start + self.piece_length).min(file_info.start + file_info.length);
+
+            let mut buf = vec![0u8; end - start];
+            self.read_at(&from, start - file_info.start, &mut buf)?;
+            self.write_at(&to, start - file_info.start, &buf)?;
+        }
+
+        // an unskipped file may already be complete from boundary pieces alone
+        let complete = {
+            let have = self.have.lock().unwrap();
+            have[self.pieces_of_file(file_info)].iter().all(|h| *h)
+        };
+        if was_skipped && file_info.length == 0 {
+            self.create_empty_file(file_index)?;
+        } else if was_skipped && complete {
+            self.finish_file(file_index)?;
+        }
+        Ok(())
+    }
+}
+
+struct Location {
+    slot: usize,
+    path: PathBuf,
+    base: usize,
+}
+
+pub(crate) struct Segment {
+    pub(crate) file_index: usize,
+    pub(crate) file_offset: usize,
+    // where the segment sits in the caller's buffer
+    pub(crate) buf: std::ops::Range<usize>,
+}
+
+// (piece index, chunk index within the piece)
+type ChunkKey = (usize, usize);
+
+/// Most recently read chunks, newest at the back.
+struct ReadCache {
+    // in bytes
+    capacity: usize,
+    size: usize,
+    entries: VecDeque<(ChunkKey, Arc<Vec<u8>>)>,
+}
+
+impl ReadCache {
+    fn new(capacity: usize) -> Self {
+        Self { capacity, size: 0, entries: VecDeque::new() }
+    }
+
+    fn get(&mut self, key: ChunkKey) -> Option<Arc<Vec<u8>>> {
+        let pos = self.entries.iter().position(|(k, _)| *k == key)?;
+        let entry = self.entries.remove(pos)?;
+        let data = entry.1.clone();
+        self.entries.push_back(entry);
+        Some(data)
+    }
+
+    fn insert(&mut self, key: ChunkKey, data: Arc<Vec<u8>>) {
+        if data.len() > self.capacity { return; }
+        if let Some(pos) = self.entries.iter().position(|(k, _)| *k == key)
+            && let Some((_, old)) = self.entries.remove(pos)
+        {
+            self.size -= old.len();
+        }
+        self.size += data.len();
+        self.entries.push_back((key, data));
+        self.shrink();
+    }
+
+    fn remove(&mut self, piece_index: usize) {
+        self.entries.retain(|((p, _), _)| *p != piece_index);
+        self.size = self.entries.iter().map(|(_, d)| d.len()).sum();
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.size = 0;
+    }
+
+    fn resize(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.shrink();
+    }
+
+    fn shrink(&mut self) {
+        while self.size > self.capacity {
+            let Some((_, data)) = self.entries.pop_front() else { break };
+            self.size -= data.len();
+        }
+    }
+}
+
+impl Storage for FileManager {
+    fn write_block(&self, piece_index: usize, begin: usize, data: &[u8]) -> anyhow::Result<()> {
+        self.read_cache.lock().unwrap().remove(piece_index);
+
+        for segment in self.segments(piece_index * self.piece_length + begin, data.len())? {
+            self.write_segment(&segment, &data[segment.buf.clone()])?;
+        }
+        Ok(())
+    }
+
+    fn read_block(&self, piece_index: usize, begin: usize, length: usize) -> anyhow::Result<Vec<u8>> {
+        let piece_size = self.piece_size(piece_index)?;
+        if begin + length > piece_size {
+            anyhow::bail!("block {begin}+{length} is outside piece {piece_index} ({piece_size} bytes)");
+        }
+
+        // a block normally sits inside one chunk, but nothing forces requests to be aligned
+        let mut block = Vec::with_capacity(length);
+        let mut offset = begin;
+        while offset < begin + length {
+            let chunk = self.read_chunk(piece_index, offset / READ_AHEAD)?;
+            let start = offset % READ_AHEAD;
+            let end = chunk.len().min(start + begin + length - offset);
+            block.extend_from_slice(&chunk[start..end]);
+            offset += end - start;
+        }
+        Ok(block)
+    }
+
+    // hashing and rechecks would only churn the cache
+    fn read_uncached(&self, piece_index: usize, begin: usize, length: usize) -> anyhow::Result<Vec<u8>> {
+        self.read_range(piece_index * self.piece_length + begin, length)
+    }
+
+    /// Current size and mtime of every output file, zeroed for files that are missing.
+    fn file_states(&self) -> anyhow::Result<Vec<FileState>> {
+        (0..self.files.len())
+            .map(|file_index| match fs::metadata(self.full_path(file_index)) {
+                Ok(meta) => {
+                    let mtime = meta
+                        .modified()?
+                        .duration_since(UNIX_EPOCH)
+                        .map(|d| d.as_nanos() as u64)
+                        .unwrap_or(0);
+                    Ok(FileState { length: meta.len(), mtime })
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(FileState { length: 0, mtime: 0 }),
+                Err(e) => Err(e.into()),
+            })
+            .collect()
+    }
+
+    fn flush(&self) -> anyhow::Result<()> {
+        self.pool.sync_all()?;
+        Ok(())
+    }
+
+    /// Moves every file under `dest`, keeping the layout inside the torrent.
+    fn move_to(&self, dest: &Path) -> anyhow::Result<()> {
+        let mut root = self.root.write().unwrap();
+        self.pool.close_all();
+
+        for file_index in 0..self.files.len() {
+            let name = self.disk_name(file_index);
+            let from = root.join(&name);
+            let to = dest.join(&name);
+            if let Some(p) = to.parent() {
+                fs::create_dir_all(p)?;
+            }
+
+            if from.exists() {
+                move_file(&from, &to)?;
+            }
+        }
+
+        let part_file = root.join(&self.part_file);
+        if part_file.exists() {
+            fs::create_dir_all(dest)?;
+            move_file(&part_file, &dest.join(&self.part_file))?;
+        }
+
+        *root = dest.to_path_buf();
+        Ok(())
+    }
+
+    fn delete(&self) -> anyhow::Result<()> {
+        self.read_cache.lock().unwrap().clear();
+        self.pool.close_all();
+        let paths = (0..self.files.len()).map(|i| self.full_path(i)).chain([self.part_file_path()]);
+        for path in paths {
+            match fs::remove_file(path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
             }
         }
+        Ok(())
+    }
+
+    /// Once every piece touching a file is on disk the file is done being
+    /// written, so its handle goes back to the pool and it loses its `.part` suffix.
+    fn piece_complete(&self, piece_index: usize) {
+        let mut have = self.have.lock().unwrap();
+        let Some(slot) = have.get_mut(piece_index) else { return };
+        *slot = true;
+
+        let piece_start = piece_index * self.piece_length;
+        let piece_end = piece_start + self.piece_length;
+        let done: Vec<usize> = self
+            .files
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| f.start < piece_end && f.start + f.length > piece_start)
+            .filter(|(_, f)| have[self.pieces_of_file(f)].iter().all(|h| *h))
+            .map(|(i, _)| i)
+            .collect();
+        drop(have);
+
+        for file_index in done {
+            self.pool.close(file_index);
+            // skipped files live in the part file; they are finished when unskipped
+            if self.priorities.read().unwrap()[file_index] != Priority::Skip
+                && let Err(e) = self.finish_file(file_index)
+            {
+                eprintln!("Failed to rename {}: {:?}", self.files[file_index].path, e);
+            }
+        }
+    }
+
+    fn piece_invalid(&self, piece_index: usize) {
+        if let Some(slot) = self.have.lock().unwrap().get_mut(piece_index) {
+            *slot = false;
+        }
+        self.read_cache.lock().unwrap().remove(piece_index);
+    }
+
+    fn set_max_open_files(&self, limit: usize) {
+        self.pool.set_capacity(limit);
+    }
+
+    fn allocate(&self, mode: AllocationMode) -> anyhow::Result<()> {
+        let priorities = self.priorities.read().unwrap().clone();
+        let wanted: Vec<(usize, &OutputFile)> = self
+            .files
+            .iter()
+            .enumerate()
+            .filter(|(i, f)| priorities[*i] != Priority::Skip && f.length > 0)
+            .collect();
 
+        // space already taken by earlier runs does not need to be found again
+        let mut needed = 0u64;
+        for (file_index, file_info) in &wanted {
+            let on_disk = fs::metadata(self.full_path(*file_index)).map(|m| allocated_bytes(&m)).unwrap_or(0);
+            needed += (file_info.length as u64).saturating_sub(on_disk);
+        }
+
+        let root = self.root.read().unwrap().clone();
+        fs::create_dir_all(&root)?;
+        let available = free_space(&root)?;
+        if needed > available {
+            anyhow::bail!("need {} more bytes in {} but only {} are free", needed, root.display(), available);
+        }
+
+        // even lazily, since no write would ever create them
+        for (file_index, file_info) in self.files.iter().enumerate() {
+            if file_info.length == 0 && priorities[file_index] != Priority::Skip {
+                self.create_empty_file(file_index)?;
+            }
+        }
+
+        if mode == AllocationMode::Lazy {
+            return Ok(());
+        }
+
+        for (file_index, file_info) in wanted {
+            let file = self.pool.get(file_index, &self.full_path(file_index), true)?;
+            let file = file.lock().unwrap();
+            let length = file_info.length as u64;
+
+            match mode {
+                AllocationMode::Sparse => {
+                    if file.metadata()?.len() < length {
+                        file.set_len(length)?;
+                    }
+                }
+                AllocationMode::Full => preallocate(&file, length)?,
+                AllocationMode::Lazy => unreachable!(),
+            }
+        }
         Ok(())
     }
+
+    fn file_lengths(&self) -> Vec<usize> {
+        self.files.iter().map(|f| f.length).collect()
+    }
+
+    fn set_file_priorities(&self, priorities: &[Priority]) -> anyhow::Result<()> {
+        if priorities.len() != self.files.len() {
+            anyhow::bail!("got {} file priorities for {} files", priorities.len(), self.files.len());
+        }
+
+        let old = std::mem::replace(&mut *self.priorities.write().unwrap(), priorities.to_vec());
+        self.read_cache.lock().unwrap().clear();
+
+        for (file_index, (&before, &after)) in old.iter().zip(priorities).enumerate() {
+            let was_skipped = before == Priority::Skip;
+            if was_skipped != (after == Priority::Skip) {
+                self.migrate(file_index, was_skipped)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// rename does not work across filesystems; fall back to copying
+fn move_file(from: &Path, to: &Path) -> anyhow::Result<()> {
+    if fs::rename(from, to).is_err() {
+        fs::copy(from, to)?;
+        fs::remove_file(from)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn allocated_bytes(meta: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    // sparse files report their full length; count the blocks actually backed
+    meta.blocks() * 512
+}
+
+#[cfg(not(unix))]
+fn allocated_bytes(meta: &fs::Metadata) -> u64 {
+    meta.len()
+}
+
+#[cfg(target_os = "linux")]
+fn preallocate(file: &std::fs::File, length: u64) -> anyhow::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: the descriptor stays open for the duration of the call
+    let ret = unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, length as libc::off_t) };
+    if ret != 0 {
+        return Err(std::io::Error::from_raw_os_error(ret).into());
+    }
+    Ok(())
+}
+
+// no portable fallocate; growing the file is the closest we get
+#[cfg(not(target_os = "linux"))]
+fn preallocate(file: &std::fs::File, length: u64) -> anyhow::Result<()> {
+    if file.metadata()?.len() < length {
+        file.set_len(length)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::{File, Info};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("torrent-rs-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn multi_file_info(piece_length: usize, files: &[(&str, usize)]) -> Info {
+        Info {
+            name: "torrent".into(),
+            piece_length,
+            pieces: Vec::new(),
+            length: None,
+            files: Some(
+                files
+                    .iter()
+                    .map(|(path, length)| File { length: *length, path: path.split('/').map(String::from).collect() })
+                    .collect(),
+            ),
+        }
+    }
+
+    #[test]
+    fn allocate_creates_wanted_empty_files() {
+        let dir = temp_dir("empty-files");
+        let info = multi_file_info(16, &[("data.bin", 10), ("sub/empty.txt", 0), ("skipped.txt", 0)]);
+        let fm = FileManager::new(&info, &dir).unwrap().with_incomplete_suffix();
+        fm.set_file_priorities(&[Priority::Normal, Priority::Normal, Priority::Skip]).unwrap();
+        fm.allocate(AllocationMode::Lazy).unwrap();
+
+        assert!(dir.join("sub/empty.txt").exists());
+        assert!(!dir.join("sub/empty.txt.part").exists());
+        assert!(!dir.join("skipped.txt").exists());
+        // lazily allocated files wait for their data
+        assert!(!dir.join("data.bin.part").exists());
+
+        fm.set_file_priorities(&[Priority::Normal; 3]).unwrap();
+        assert!(dir.join("skipped.txt").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reads_blocks_across_file_boundaries() {
+        let dir = temp_dir("cross-read");
+        let info = multi_file_info(8, &[("a.bin", 5), ("sub/b.bin", 7), ("c.bin", 4)]);
+        let data: Vec<u8> = (0..16).collect();
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("a.bin"), &data[..5]).unwrap();
+        fs::write(dir.join("sub/b.bin"), &data[5..12]).unwrap();
+        fs::write(dir.join("c.bin"), &data[12..]).unwrap();
+
+        let fm = FileManager::new(&info, &dir).unwrap();
+        // crosses from a.bin into sub/b.bin, then from sub/b.bin into c.bin
+        assert_eq!(fm.read_block(0, 3, 5).unwrap(), data[3..8]);
+        assert_eq!(fm.read_block(1, 0, 8).unwrap(), data[8..16]);
+        // served from the cache the second time round
+        assert_eq!(fm.read_block(0, 0, 8).unwrap(), data[..8]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn short_files_are_reported_by_name() {
+        let dir = temp_dir("short-read");
+        let info = multi_file_info(8, &[("a.bin", 5), ("b.bin", 7)]);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.bin"), [0u8; 5]).unwrap();
+        fs::write(dir.join("b.bin"), [0u8; 2]).unwrap();
+
+        let fm = FileManager::new(&info, &dir).unwrap();
+        let err = fm.read_block(1, 0, 4).unwrap_err().to_string();
+        assert!(err.contains("b.bin is 2 bytes, need 7"), "{err}");
+
+        fs::remove_file(dir.join("a.bin")).unwrap();
+        let err = FileManager::new(&info, &dir).unwrap().read_block(0, 0, 4).unwrap_err().to_string();
+        assert!(err.contains("a.bin is missing"), "{err}");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn allocation_modes_size_and_reserve_wanted_files() {
+        let dir = temp_dir("allocate");
+        let info = multi_file_info(16, &[("a.bin", 100_000), ("b.bin", 50_000), ("skipped.bin", 10)]);
+        let fm = FileManager::new(&info, &dir).unwrap();
+        fm.set_file_priorities(&[Priority::Normal, Priority::Normal, Priority::Skip]).unwrap();
+
+        fm.allocate(AllocationMode::Lazy).unwrap();
+        assert!(!dir.join("a.bin").exists());
+
+        fm.allocate(AllocationMode::Sparse).unwrap();
+        assert_eq!(fs::metadata(dir.join("a.bin")).unwrap().len(), 100_000);
+        assert_eq!(fs::metadata(dir.join("b.bin")).unwrap().len(), 50_000);
+        assert!(!dir.join("skipped.bin").exists());
+
+        fm.allocate(AllocationMode::Full).unwrap();
+        let meta = fs::metadata(dir.join("a.bin")).unwrap();
+        assert_eq!(meta.len(), 100_000);
+        assert!(allocated_bytes(&meta) >= 100_000);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn allocation_fails_early_without_enough_space() {
+        let dir = temp_dir("allocate-full-disk");
+        // an exabyte or so; no test machine has that spare
+        let info = multi_file_info(1 << 50, &[("huge.bin", 1 << 60)]);
+        let fm = FileManager::new(&info, &dir).unwrap();
+
+        let err = fm.allocate(AllocationMode::Sparse).unwrap_err().to_string();
+        assert!(err.starts_with(&format!("need {} more bytes", 1u64 << 60)), "{err}");
+        assert!(!dir.join("huge.bin").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn part_files_are_renamed_when_done_and_moved_as_they_are() {
+        let dir = temp_dir("part-rename");
+        let dest = temp_dir("part-rename-dest");
+        let info = multi_file_info(8, &[("a.bin", 5), ("sub/b.bin", 7), ("c.bin", 4)]);
+        let data: Vec<u8> = (0..16).collect();
+        let fm = FileManager::new(&info, &dir).unwrap().with_incomplete_suffix();
+
+        fm.write_block(0, 0, &data[..8]).unwrap();
+        assert!(dir.join("a.bin.part").exists() && dir.join("sub/b.bin.part").exists());
+        fm.piece_complete(0);
+        // b.bin still waits for the second piece
+        assert!(dir.join("a.bin").exists() && !dir.join("a.bin.part").exists());
+        assert!(dir.join("sub/b.bin.part").exists());
+
+        fm.move_to(&dest).unwrap();
+        assert!(dest.join("a.bin").exists() && dest.join("sub/b.bin.part").exists());
+        assert!(!dir.join("a.bin").exists() && !dir.join("sub/b.bin.part").exists());
+
+        fm.write_block(1, 0, &data[8..]).unwrap();
+        fm.piece_complete(1);
+        assert_eq!(fs::read(dest.join("sub/b.bin")).unwrap(), data[5..12]);
+        assert_eq!(fs::read(dest.join("c.bin")).unwrap(), data[12..]);
+        assert!(!dest.join("sub/b.bin.part").exists());
+        assert_eq!(fm.read_block(0, 0, 8).unwrap(), data[..8]);
+
+        let _ = fs::remove_dir_all(&dir);
+        fs::remove_dir_all(&dest).unwrap();
+    }
 }
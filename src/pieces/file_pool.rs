@@ -0,0 +1,169 @@
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+pub const DEFAULT_MAX_OPEN_FILES: usize = 64;
+
+struct PoolEntry {
+    file_index: usize,
+    writable: bool,
+    file: Arc<Mutex<File>>,
+}
+
+/// Open file handles shared by reads and writes, least recently used evicted first.
+///
+/// Files are opened read-only until something has to be written to them.
+pub struct FilePool {
+    inner: Mutex<PoolInner>,
+}
+
+struct PoolInner {
+    capacity: usize,
+    // most recently used at the back
+    entries: VecDeque<PoolEntry>,
+}
+
+impl FilePool {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Mutex::new(PoolInner { capacity: capacity.max(1), entries: VecDeque::new() }),
+        }
+    }
+
+    /// Handle for `file_index`, opening `path` if it is not in the pool or
+    /// only open read-only when `writable` is needed.
+    pub fn get(&self, file_index: usize, path: &Path, writable: bool) -> std::io::Result<Arc<Mutex<File>>> {
+        let mut inner = self.inner.lock().unwrap();
+
+        if let Some(pos) = inner.entries.iter().position(|e| e.file_index == file_index) {
+            let entry = inner.entries.remove(pos).unwrap();
+            if entry.writable || !writable {
+                let file = entry.file.clone();
+                inner.entries.push_back(entry);
+                return Ok(file);
+            }
+            // read-only handle but we need to write: drop it and reopen below
+        }
+
+        let file = if writable {
+            // files are created on their first write
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?
+        } else {
+            File::open(path)?
+        };
+        let file = Arc::new(Mutex::new(file));
+
+        while inner.entries.len() >= inner.capacity {
+            inner.entries.pop_front();
+        }
+        inner.entries.push_back(PoolEntry { file_index, writable, file: file.clone() });
+        Ok(file)
+    }
+
+    pub fn close(&self, file_index: usize) {
+        self.inner.lock().unwrap().entries.retain(|e| e.file_index != file_index);
+    }
+
+    pub fn close_all(&self) {
+        self.inner.lock().unwrap().entries.clear();
+    }
+
+    pub fn set_capacity(&self, capacity: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.capacity = capacity.max(1);
+        while inner.entries.len() > inner.capacity {
+            inner.entries.pop_front();
+        }
+    }
+
+    /// Flushes every handle that was opened for writing.
+    pub fn sync_all(&self) -> std::io::Result<()> {
+        let writable: Vec<Arc<Mutex<File>>> = self
+            .inner
+            .lock()
+            .unwrap()
+            .entries
+            .iter()
+            .filter(|e| e.writable)
+            .map(|e| e.file.clone())
+            .collect();
+
+        for file in writable {
+            file.lock().unwrap().sync_all()?;
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("torrent-rs-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn reuses_handles_and_evicts_the_least_recently_used() {
+        let dir = temp_dir("pool-lru");
+        let pool = FilePool::new(2);
+        let paths: Vec<_> = (0..3).map(|i| dir.join(format!("sub/{i}"))).collect();
+
+        let first = pool.get(0, &paths[0], true).unwrap();
+        assert!(paths[0].exists());
+        assert!(Arc::ptr_eq(&first, &pool.get(0, &paths[0], false).unwrap()));
+
+        pool.get(1, &paths[1], true).unwrap();
+        // file 0 was used more recently than file 1, so 1 goes
+        pool.get(0, &paths[0], false).unwrap();
+        pool.get(2, &paths[2], true).unwrap();
+        assert_eq!(pool.len(), 2);
+        assert!(Arc::ptr_eq(&first, &pool.get(0, &paths[0], false).unwrap()));
+        assert!(!Arc::ptr_eq(&first, &pool.get(1, &paths[1], false).unwrap()));
+
+        pool.set_capacity(1);
+        assert_eq!(pool.len(), 1);
+        pool.close_all();
+        assert!(pool.is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reopens_read_only_handles_for_writing() {
+        let dir = temp_dir("pool-upgrade");
+        let path = dir.join("file");
+        let pool = FilePool::new(4);
+
+        // reads never create anything
+        assert_eq!(pool.get(0, &path, false).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+        assert!(!dir.exists());
+
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&path, b"old").unwrap();
+        let read_only = pool.get(0, &path, false).unwrap();
+        assert!(read_only.lock().unwrap().write_all(b"x").is_err());
+
+        let writable = pool.get(0, &path, true).unwrap();
+        assert!(!Arc::ptr_eq(&read_only, &writable));
+        writable.lock().unwrap().write_all(b"new").unwrap();
+        pool.sync_all().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+        assert_eq!(pool.len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
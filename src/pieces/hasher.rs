@@ -0,0 +1,127 @@
+use sha1::{self, Digest};
+use std::sync::{mpsc as std_mpsc, Arc, Mutex};
+use std::thread;
+
+use tokio::sync::mpsc;
+
+use crate::pieces::storage::Storage;
+
+// how much of a piece is read back from storage at a time
+const READ_CHUNK: usize = 1 << 20;
+const BLOCK_SIZE: usize = 16384;
+
+/// Verdict for a piece handed to the [`Hasher`].
+#[derive(Debug)]
+pub struct HashResult {
+    pub piece_index: usize,
+    pub passed: bool,
+    // SHA-1 of every block, for downloaded pieces that failed or were asked to have them
+    pub block_digests: Vec<[u8; 20]>,
+    // submitted with `check` rather than `submit`
+    pub recheck: bool,
+}
+
+struct HashJob {
+    piece_index: usize,
+    // SHA-1 over the first `hashed` bytes, fed as blocks arrived in order
+    state: sha1::Sha1,
+    hashed: usize,
+    length: usize,
+    expected: [u8; 20],
+    block_digests: bool,
+    recheck: bool,
+}
+
+/// SHA-1 checks on a pool of OS threads, so hashing a large piece never holds
+/// up the `PieceManager` lock or the async runtime.
+///
+/// Pieces are already on disk when they get here; whatever part was not hashed
+/// on arrival is read back from storage in chunks, so a piece is never fully resident.
+pub struct Hasher {
+    jobs: std_mpsc::Sender<HashJob>,
+}
+
+impl Hasher {
+    pub fn new(storage: Arc<dyn Storage>, threads: usize) -> (Self, mpsc::UnboundedReceiver<HashResult>) {
+        let (jobs, job_rx) = std_mpsc::channel::<HashJob>();
+        let (results, rx) = mpsc::unbounded_channel();
+        let job_rx = Arc::new(Mutex::new(job_rx));
+
+        for i in 0..threads.max(1) {
+            let job_rx = job_rx.clone();
+            let results = results.clone();
+            let storage = storage.clone();
+            thread::Builder::new()
+                .name(format!("hasher-{i}"))
+                .spawn(move || loop {
+                    // the lock is only held while waiting for the next job
+                    let job = match job_rx.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        Err(_) => return, // hasher dropped
+                    };
+
+                    let (piece_index, recheck) = (job.piece_index, job.recheck);
+                    let (length, want_digests) = (job.length, job.block_digests);
+                    let passed = match finish(storage.as_ref(), job) {
+                        Ok(passed) => passed,
+                        Err(e) => {
+                            eprintln!("Failed to read piece {} for hashing: {:?}", piece_index, e);
+                            false
+                        }
+                    };
+
+                    // a failed piece always gets them, to work out who sent the bad block
+                    let block_digests = if !recheck && (!passed || want_digests) {
+                        digest_blocks(storage.as_ref(), piece_index, length).unwrap_or_default()
+                    } else {
+                        Vec::new()
+                    };
+                    if results.send(HashResult { piece_index, passed, block_digests, recheck }).is_err() { return; }
+                })
+                .expect("failed to spawn hasher thread");
+        }
+
+        (Self { jobs }, rx)
+    }
+
+    /// Threads to use by default: one per core.
+    pub fn default_threads() -> usize {
+        thread::available_parallelism().map(|n| n.get()).unwrap_or(2)
+    }
+
+    /// Checks a piece whose blocks are all in storage. `state` has already
+    /// consumed the first `hashed` bytes. With `block_digests` the result
+    /// carries per-block hashes even if the piece passes.
+    pub fn submit(&self, piece_index: usize, state: sha1::Sha1, hashed: usize, length: usize, expected: [u8; 20], block_digests: bool) {
+        // workers only go away together with `self`
+        let _ = self.jobs.send(HashJob { piece_index, state, hashed, length, expected, block_digests, recheck: false });
+    }
+
+    /// Hashes a piece straight from storage, whatever state it was downloaded in.
+    pub fn check(&self, piece_index: usize, length: usize, expected: [u8; 20]) {
+        let state = sha1::Sha1::new();
+        let _ = self.jobs.send(HashJob { piece_index, state, hashed: 0, length, expected, block_digests: false, recheck: true });
+    }
+}
+
+fn finish(storage: &dyn Storage, mut job: HashJob) -> anyhow::Result<bool> {
+    let mut offset = job.hashed;
+    while offset < job.length {
+        let len = READ_CHUNK.min(job.length - offset);
+        job.state.update(storage.read_uncached(job.piece_index, offset, len)?);
+        offset += len;
+    }
+
+    let computed: [u8; 20] = job.state.finalize().into();
+    Ok(computed == job.expected)
+}
+
+fn digest_blocks(storage: &dyn Storage, piece_index: usize, length: usize) -> anyhow::Result<Vec<[u8; 20]>> {
+    (0..length)
+        .step_by(BLOCK_SIZE)
+        .map(|begin| {
+            let block = storage.read_uncached(piece_index, begin, BLOCK_SIZE.min(length - begin))?;
+            Ok(sha1::Sha1::digest(&block).into())
+        })
+        .collect()
+}
@@ -0,0 +1,175 @@
+use memmap2::MmapMut;
+use std::fs::OpenOptions;
+use std::path::Path;
+use std::sync::RwLock;
+
+use crate::pieces::file_manager::FileManager;
+use crate::pieces::picker::Priority;
+use crate::pieces::resume::FileState;
+use crate::pieces::storage::{AllocationMode, Storage};
+
+// a write only locks the file it lands in, so seeding from the others carries on
+type FileMap = Option<RwLock<MmapMut>>;
+
+/// Memory-maps every output file. Reads for seeding come straight out of the
+/// page cache without a syscall per block, but `read_block` still copies the
+/// block out of the map, so this is not zero-copy. The file layout is borrowed
+/// from `FileManager`.
+///
+/// Every file is created at its final size up front, so the backend cannot
+/// skip files or allocate lazily: `set_file_priorities` refuses `Skip` and
+/// `allocate` refuses `Lazy`. Files keep their final names while downloading,
+/// and with no read cache or renames to track, `piece_complete` and
+/// `piece_invalid` have nothing to do.
+pub struct MmapStorage {
+    fm: FileManager,
+    // one map per file; empty files cannot be mapped and stay `None`
+    maps: RwLock<Vec<FileMap>>,
+}
+
+impl MmapStorage {
+    pub fn new(info: &crate::torrent::Info, save_path: &Path) -> anyhow::Result<Self> {
+        let fm = FileManager::new(info, save_path)?;
+        let maps = Self::map_files(&fm)?;
+        Ok(Self { fm, maps: RwLock::new(maps) })
+    }
+
+    fn map_files(fm: &FileManager) -> anyhow::Result<Vec<FileMap>> {
+        fm.files
+            .iter()
+            .enumerate()
+            .map(|(file_index, file_info)| {
+                if file_info.length == 0 { return Ok(None); }
+
+                let path = fm.full_path(file_index);
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                let file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(&path)?;
+                // a map cannot grow, so the file needs its final size up front
+                file.set_len(file_info.length as u64)?;
+
+                // SAFETY: the files belong to this torrent and are only touched through this storage
+                let map = unsafe { MmapMut::map_mut(&file)? };
+                Ok(Some(RwLock::new(map)))
+            })
+            .collect()
+    }
+}
+
+impl Storage for MmapStorage {
+    fn read_block(&self, piece_index: usize, begin: usize, length: usize) -> anyhow::Result<Vec<u8>> {
+        let maps = self.maps.read().unwrap();
+        let mut data = vec![0u8; length];
+
+        for segment in self.fm.segments(piece_index * self.fm.piece_length + begin, length)? {
+            if let Some(map) = &maps[segment.file_index] {
+                let map = map.read().unwrap();
+                let offset = segment.file_offset;
+                data[segment.buf.clone()].copy_from_slice(&map[offset..offset + segment.buf.len()]);
+            }
+        }
+        Ok(data)
+    }
+
+    fn write_block(&self, piece_index: usize, begin: usize, data: &[u8]) -> anyhow::Result<()> {
+        let maps = self.maps.read().unwrap();
+
+        for segment in self.fm.segments(piece_index * self.fm.piece_length + begin, data.len())? {
+            if let Some(map) = &maps[segment.file_index] {
+                let mut map = map.write().unwrap();
+                let offset = segment.file_offset;
+                map[offset..offset + segment.buf.len()].copy_from_slice(&data[segment.buf.clone()]);
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> anyhow::Result<()> {
+        for map in self.maps.read().unwrap().iter().flatten() {
+            map.read().unwrap().flush()?;
+        }
+        Ok(())
+    }
+
+    fn move_to(&self, dest: &Path) -> anyhow::Result<()> {
+        let mut maps = self.maps.write().unwrap();
+        for map in maps.iter().flatten() {
+            map.read().unwrap().flush()?;
+        }
+        maps.clear();
+
+        self.fm.move_to(dest)?;
+        *maps = Self::map_files(&self.fm)?;
+        Ok(())
+    }
+
+    // the maps already span every file; this only checks space and reserves blocks
+    fn allocate(&self, mode: AllocationMode) -> anyhow::Result<()> {
+        if mode == AllocationMode::Lazy {
+            anyhow::bail!("memory-mapped storage sizes every file up front, use sparse or full allocation");
+        }
+        self.fm.allocate(mode)
+    }
+
+    fn delete(&self) -> anyhow::Result<()> {
+        self.maps.write().unwrap().clear();
+        self.fm.delete()
+    }
+
+    fn file_states(&self) -> anyhow::Result<Vec<FileState>> {
+        self.fm.file_states()
+    }
+
+    fn file_lengths(&self) -> Vec<usize> {
+        self.fm.file_lengths()
+    }
+
+    fn set_file_priorities(&self, priorities: &[Priority]) -> anyhow::Result<()> {
+        if priorities.contains(&Priority::Skip) {
+            anyhow::bail!("memory-mapped storage keeps every file on disk and cannot skip any");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::{File, Info};
+
+    #[test]
+    fn blocks_span_files_and_unsupported_modes_are_refused() {
+        let dir = std::env::temp_dir().join(format!("torrent-rs-mmap-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let info = Info {
+            name: "torrent".into(),
+            piece_length: 8,
+            pieces: Vec::new(),
+            length: None,
+            files: Some(vec![
+                File { length: 5, path: vec!["a.bin".into()] },
+                File { length: 0, path: vec!["empty".into()] },
+                File { length: 7, path: vec!["b.bin".into()] },
+            ]),
+        };
+        let storage = MmapStorage::new(&info, &dir).unwrap();
+
+        storage.write_block(0, 2, &[1, 2, 3, 4, 5, 6]).unwrap();
+        storage.flush().unwrap();
+        assert_eq!(storage.read_block(0, 0, 8).unwrap(), [0, 0, 1, 2, 3, 4, 5, 6]);
+        assert_eq!(std::fs::read(dir.join("b.bin")).unwrap(), [4, 5, 6, 0, 0, 0, 0]);
+
+        assert!(storage.allocate(AllocationMode::Lazy).is_err());
+        assert!(storage.set_file_priorities(&[Priority::Normal, Priority::Skip, Priority::Normal]).is_err());
+        assert!(storage.set_file_priorities(&[Priority::Normal; 3]).is_ok());
+
+        drop(storage);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,2 +1,10 @@
+pub mod disk_io;
 pub mod file_manager;
-pub mod piece_manager;
\ No newline at end of file
+pub mod file_pool;
+pub mod hasher;
+pub mod mmap_storage;
+pub mod picker;
+pub mod piece_manager;
+pub mod resume;
+pub mod sanitize;
+pub mod storage;
\ No newline at end of file
@@ -0,0 +1,101 @@
+use std::time::Instant;
+
+use crate::pieces::piece_manager::Piece;
+
+/// Download priority of a single piece. `Skip` pieces are never requested.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Priority {
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// Everything a picker may look at when choosing what to ask a peer for.
+pub struct PickerContext<'a> {
+    /// How many connected peers have each piece.
+    pub availability: &'a [u32],
+    /// Pieces the peer we are about to request from has.
+    pub peer_bitfield: &'a [bool],
+    pub priorities: &'a [Priority],
+    /// Per-piece download state, including which pieces are partially requested or received.
+    pub pieces: &'a [Piece],
+    pub deadlines: &'a [Option<Instant>],
+    pub playback_position: usize,
+}
+
+impl PickerContext<'_> {
+    /// Pieces the peer has that we still want, in index order.
+    pub fn candidates(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.pieces.len()).filter(|&i| {
+            self.peer_bitfield[i] && !self.pieces[i].is_complete() && self.priorities[i] != Priority::Skip
+        })
+    }
+}
+
+/// Decides the order in which pieces are requested from a peer.
+///
+/// `PieceManager` walks the returned list and hands out the first free block it
+/// finds, so a picker only has to rank pieces, not track blocks.
+pub trait PiecePicker: Send {
+    fn pick(&mut self, ctx: &PickerContext<'_>) -> Vec<usize>;
+}
+
+/// Rarest pieces first, partially downloaded pieces before new ones.
+#[derive(Default)]
+pub struct RarestFirstPicker;
+
+impl PiecePicker for RarestFirstPicker {
+    fn pick(&mut self, ctx: &PickerContext<'_>) -> Vec<usize> {
+        let mut order: Vec<usize> = ctx.candidates().collect();
+        order.sort_by_key(|&i| {
+            (std::cmp::Reverse(ctx.priorities[i]), !ctx.pieces[i].is_started(), ctx.availability[i], i)
+        });
+        order
+    }
+}
+
+/// Strict index order within each priority.
+#[derive(Default)]
+pub struct SequentialPicker;
+
+impl PiecePicker for SequentialPicker {
+    fn pick(&mut self, ctx: &PickerContext<'_>) -> Vec<usize> {
+        let mut order: Vec<usize> = ctx.candidates().collect();
+        order.sort_by_key(|&i| (std::cmp::Reverse(ctx.priorities[i]), i));
+        order
+    }
+}
+
+/// Streaming picker: pieces with a deadline by deadline, then the lookahead
+/// window after the playback position in order, then rarest-first for the rest.
+pub struct DeadlinePicker {
+    lookahead: usize,
+}
+
+impl DeadlinePicker {
+    pub fn new(lookahead: usize) -> Self {
+        Self { lookahead }
+    }
+}
+
+impl Default for DeadlinePicker {
+    fn default() -> Self {
+        Self::new(8)
+    }
+}
+
+impl PiecePicker for DeadlinePicker {
+    fn pick(&mut self, ctx: &PickerContext<'_>) -> Vec<usize> {
+        let window = ctx.playback_position..ctx.playback_position + self.lookahead;
+        let mut order: Vec<usize> = ctx.candidates().collect();
+        order.sort_by_key(|&i| {
+            let deadline = ctx.deadlines[i];
+            let in_window = window.contains(&i);
+            let rarity = if in_window { 0 } else { ctx.availability[i] };
+            (deadline.is_none(), deadline, !in_window, std::cmp::Reverse(ctx.priorities[i]), rarity, i)
+        });
+        order
+    }
+}
@@ -1,33 +1,218 @@
-use sha1::{self, Digest};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use sha1::Digest;
+use tokio::sync::{broadcast, mpsc, watch, Mutex};
 
-use crate::pieces::file_manager::FileManager;
+use crate::pieces::disk_io::{DiskEvent, DiskIo, DEFAULT_DISK_THREADS, DEFAULT_READ_QUEUE, DEFAULT_WRITE_QUEUE};
+use crate::pieces::hasher::{HashResult, Hasher};
+use crate::pieces::picker::{PickerContext, PiecePicker, Priority};
+use crate::pieces::resume::{FileState, PartialPiece, ResumeData};
+use crate::pieces::storage::Storage;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum BlockState {
     NotRequested,
     Requested,
+    // received and queued for the disk threads
+    Writing,
     Received,
 }
 
+/// Notifications fanned out from the piece manager to every peer connection.
+#[derive(Clone, Debug)]
+pub enum PieceEvent {
+    /// A block arrived during endgame; the listed peers still have a request out for it.
+    Cancel { piece_index: usize, begin: usize, length: usize, peers: Vec<SocketAddr> },
+    /// A piece passed its hash check and can be announced with `have`.
+    Verified(usize),
+    /// A verified piece reached storage and can be served.
+    Written(usize),
+    /// Downloading stopped, e.g. because the disk is full.
+    Paused,
+    Resumed,
+    /// Every wanted piece is on disk and storage moved to the completed directory.
+    Moved(PathBuf),
+    /// Buffered blocks dropped back under the memory cap; requesting can continue.
+    BuffersFreed,
+    /// Connections to this address should be dropped.
+    Banned(IpAddr),
+}
+
+/// A block a peer sent that we cannot accept. Any of these gets the peer
+/// disconnected and counts against it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockError {
+    UnknownPiece(usize),
+    Unaligned { piece_index: usize, begin: usize },
+    OutOfRange { piece_index: usize, begin: usize },
+    WrongLength { piece_index: usize, begin: usize, expected: usize, got: usize },
+    Unrequested { piece_index: usize, begin: usize },
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockError::UnknownPiece(index) => write!(f, "block for unknown piece {index}"),
+            BlockError::Unaligned { piece_index, begin } => {
+                write!(f, "block at {begin} in piece {piece_index} is not on a block boundary")
+            }
+            BlockError::OutOfRange { piece_index, begin } => write!(f, "block at {begin} is past the end of piece {piece_index}"),
+            BlockError::WrongLength { piece_index, begin, expected, got } => {
+                write!(f, "block at {begin} in piece {piece_index} is {got} bytes, expected {expected}")
+            }
+            BlockError::Unrequested { piece_index, begin } => write!(f, "unrequested block at {begin} in piece {piece_index}"),
+        }
+    }
+}
+
+impl std::error::Error for BlockError {}
+
+/// Download state of one piece. Block data is not kept here: blocks go
+/// straight to storage and only a running hash stays behind.
 pub struct Piece {
     index: usize,
-    data: Vec<u8>,
+    length: usize,
     block_status: Vec<BlockState>,
-    bytes_written: u32,
+    requested_from: Vec<Vec<SocketAddr>>,
+    // who sent each block; `None` for blocks restored from resume data
+    contributors: Vec<Option<IpAddr>>,
+    // after a failed hash check: who sent each block and a digest of what they sent
+    suspects: Vec<Vec<(IpAddr, [u8; 20])>>,
+    // SHA-1 over the blocks that arrived in order, and how far it got
+    hash_state: sha1::Sha1,
+    hashed: usize,
     is_complete: bool,
+    // all blocks are on disk and out with the hasher
+    is_hashing: bool,
 }
 
 impl Piece {
     fn maybe_init(&mut self, piece_len: usize, block_size: usize) {
-        if self.data.is_empty() {
-            let num_blocks = (piece_len + block_size - 1) / block_size;
-            self.data = vec![0u8; piece_len];
+        if self.block_status.is_empty() {
+            let num_blocks = piece_len.div_ceil(block_size);
+            self.length = piece_len;
             self.block_status = vec![BlockState::NotRequested; num_blocks];
+            self.requested_from = vec![Vec::new(); num_blocks];
+            self.contributors = vec![None; num_blocks];
         }
     }
+
+    /// Starts the piece over, e.g. after it failed its hash check.
+    fn reset(&mut self) {
+        self.block_status.fill(BlockState::NotRequested);
+        self.requested_from.iter_mut().for_each(Vec::clear);
+        self.contributors.fill(None);
+        self.hash_state = sha1::Sha1::new();
+        self.hashed = 0;
+    }
+
+    /// Drops all per-block state; the piece is either done or not started.
+    fn clear(&mut self) {
+        self.block_status = Vec::new();
+        self.requested_from = Vec::new();
+        self.contributors = Vec::new();
+        self.suspects = Vec::new();
+        self.hash_state = sha1::Sha1::new();
+        self.hashed = 0;
+        self.is_hashing = false;
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.is_complete
+    }
+
+    pub fn is_hashing(&self) -> bool {
+        self.is_hashing
+    }
+
+    /// True once any block of the piece has been handed out.
+    pub fn is_started(&self) -> bool {
+        !self.block_status.is_empty()
+    }
+
+    pub fn blocks_received(&self) -> usize {
+        self.block_status.iter().filter(|b| matches!(b, BlockState::Writing | BlockState::Received)).count()
+    }
+
+    pub fn num_blocks(&self) -> usize {
+        self.block_status.len()
+    }
+
+    fn has_unrequested_blocks(&self) -> bool {
+        // an uninitialised piece has not handed out any of its blocks yet
+        self.block_status.is_empty() || self.block_status.contains(&BlockState::NotRequested)
+    }
+}
+
+/// Progress of a hash check over the data on disk.
+#[derive(Clone, Copy, Debug)]
+pub struct CheckProgress {
+    pub checked: usize,
+    pub valid: usize,
+    pub total: usize,
 }
 
 const BLOCK_SIZE: usize = 16384;
+// protocol violations tolerated before an address is banned
+const MAX_STRIKES: u32 = 3;
+// how often `stop` looks whether the disk threads have caught up
+const WRITE_POLL_INTERVAL: Duration = Duration::from_millis(20);
+/// Default cap on block memory: requested blocks plus received ones not yet on disk.
+pub const DEFAULT_MAX_BUFFERED: usize = 64 * 1024 * 1024;
+
+/// Block memory shared by every torrent in a session, the way the connection
+/// semaphore is: blocks that are requested or waiting for disk, each holding
+/// up to `BLOCK_SIZE`. The cap is soft; torrents racing for the last blocks
+/// can go over it by a few.
+pub struct BufferBudget {
+    max_blocks: AtomicUsize,
+    used: AtomicUsize,
+    // bumped when the budget stops being full, so throttled torrents carry on
+    freed: watch::Sender<()>,
+}
+
+impl BufferBudget {
+    pub fn new(bytes: usize) -> Arc<Self> {
+        Arc::new(Self {
+            max_blocks: AtomicUsize::new((bytes / BLOCK_SIZE).max(1)),
+            used: AtomicUsize::new(0),
+            freed: watch::channel(()).0,
+        })
+    }
+
+    pub fn set_max(&self, bytes: usize) {
+        self.max_blocks.store((bytes / BLOCK_SIZE).max(1), Ordering::Relaxed);
+        // raising the cap may let throttled torrents carry on
+        self.freed.send_replace(());
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.used.load(Ordering::Relaxed) >= self.max_blocks.load(Ordering::Relaxed)
+    }
+
+    /// Bytes held by all torrents together.
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed) * BLOCK_SIZE
+    }
+
+    fn reserve(&self, blocks: usize) {
+        self.used.fetch_add(blocks, Ordering::Relaxed);
+    }
+
+    fn release(&self, blocks: usize) {
+        if blocks == 0 { return; }
+        let before = self.used.fetch_sub(blocks, Ordering::Relaxed);
+        let max = self.max_blocks.load(Ordering::Relaxed);
+        if before >= max && before - blocks < max {
+            self.freed.send_replace(());
+        }
+    }
+}
 
 pub struct PieceManager {
     pub num_pieces: usize,
@@ -36,12 +221,46 @@ pub struct PieceManager {
 
     piece_hashes: Vec<[u8; 20]>,
     pieces: Vec<Piece>,
+    endgame: bool,
+    paused: bool,
+    // progress of a running recheck; no blocks are handed out until it ends
+    recheck: Option<watch::Sender<CheckProgress>>,
+
+    // blocks that are requested or waiting for disk, each holding up to BLOCK_SIZE of memory
+    reserved_blocks: usize,
+    // the cap they count against, possibly shared with other torrents
+    buffers: Arc<BufferBudget>,
+    // next_block turned someone away because of the cap
+    throttled: bool,
+
+    // piece selection
+    picker: Box<dyn PiecePicker>,
+    availability: Vec<u32>,
+    priorities: Vec<Priority>,
+    deadlines: Vec<Option<Instant>>,
+    playback_position: usize,
 
-    tx: tokio::sync::mpsc::Sender<(usize, Vec<u8>)>,
+    // transfer totals, carried over in resume data
+    pub downloaded: u64,
+    pub uploaded: u64,
+
+    storage: Arc<dyn Storage>,
+    disk: DiskIo,
+    disk_events: Option<mpsc::UnboundedReceiver<DiskEvent>>,
+    hasher: Hasher,
+    hash_results: Option<mpsc::UnboundedReceiver<HashResult>>,
+    // set once the disk threads report a verified piece as written
+    written: Vec<bool>,
+    // where finished downloads go; taken once the move has been started
+    completed_dir: Option<PathBuf>,
+    // misbehaving peers, by address so reconnecting on another port does not help
+    strikes: HashMap<IpAddr, u32>,
+    banned: HashSet<IpAddr>,
+    events: broadcast::Sender<PieceEvent>,
 }
 
 impl PieceManager {
-    pub fn new(piece_length: usize, total_length: usize, piece_hashes: &[u8], fm: FileManager) -> Self {
+    pub fn new(piece_length: usize, total_length: usize, piece_hashes: &[u8], storage: Arc<dyn Storage>, picker: Box<dyn PiecePicker>) -> Self {
         // split hashes into 20-byte arrays
         let hashes: Vec<[u8; 20]> = piece_hashes
             .chunks(20)
@@ -58,22 +277,20 @@ impl PieceManager {
         let pieces_vec: Vec<Piece> = (0..num_pieces)
             .map(|i| Piece {
                 index: i,
-                data: Vec::new(),
+                length: 0,
                 block_status: Vec::new(),
-                bytes_written: 0,
+                requested_from: Vec::new(),
+                contributors: Vec::new(),
+                suspects: Vec::new(),
+                hash_state: sha1::Sha1::new(),
+                hashed: 0,
                 is_complete: false,
+                is_hashing: false,
             })
             .collect();
-        let (tx, mut rx) = tokio::sync::mpsc::channel::<(usize, Vec<u8>)>(200);
-
-        tokio::spawn(async move {
-            let fm = fm;
-            while let Some((piece_index, data)) = rx.recv().await {
-                if let Err(e) = fm.write_piece(piece_index, &data, piece_length) {
-                    eprintln!("Error writing piece {}: {:?}", piece_index, e);
-                }
-            }
-        });
+        let (events, _) = broadcast::channel(256);
+        let (hasher, hash_results) = Hasher::new(storage.clone(), Hasher::default_threads());
+        let (disk, disk_events) = DiskIo::new(storage.clone(), DEFAULT_DISK_THREADS, DEFAULT_READ_QUEUE, DEFAULT_WRITE_QUEUE);
 
         Self {
             num_pieces,
@@ -81,78 +298,1119 @@ impl PieceManager {
             total_length,
             piece_hashes: hashes,
             pieces: pieces_vec,
-            tx: tx,
+            endgame: false,
+            paused: false,
+            recheck: None,
+            reserved_blocks: 0,
+            buffers: BufferBudget::new(DEFAULT_MAX_BUFFERED),
+            throttled: false,
+            picker,
+            availability: vec![0; num_pieces],
+            priorities: vec![Priority::default(); num_pieces],
+            deadlines: vec![None; num_pieces],
+            playback_position: 0,
+            downloaded: 0,
+            uploaded: 0,
+            storage,
+            disk,
+            disk_events: Some(disk_events),
+            hasher,
+            hash_results: Some(hash_results),
+            written: vec![false; num_pieces],
+            completed_dir: None,
+            strikes: HashMap::new(),
+            banned: HashSet::new(),
+            events,
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<PieceEvent> {
+        self.events.subscribe()
+    }
+
+    /// Feeds hash verdicts and disk completions back into the manager. Until
+    /// this runs, blocks are never marked as written and no piece ever gets verified.
+    pub fn spawn_completion_loop(pm: Arc<Mutex<PieceManager>>) {
+        tokio::spawn(async move {
+            let (Some(mut hash_rx), Some(mut disk_rx), mut freed) = ({
+                let mut guard = pm.lock().await;
+                (guard.hash_results.take(), guard.disk_events.take(), guard.buffers.freed.subscribe())
+            }) else { return };
+
+            loop {
+                tokio::select! {
+                    Some(result) = hash_rx.recv() => pm.lock().await.handle_hash_result(result),
+                    Some(event) = disk_rx.recv() => pm.lock().await.handle_disk_event(event),
+                    // another torrent gave back buffer memory
+                    Ok(()) = freed.changed() => pm.lock().await.release_reserved(0),
+                    else => return,
+                }
+            }
+        });
+    }
+
+    pub fn handle_disk_event(&mut self, event: DiskEvent) {
+        match event {
+            DiskEvent::Written { piece_index, begin } => {
+                // a recheck may have reset the piece while the block was queued
+                let Some(state) = self.pieces[piece_index].block_status.get_mut(begin / BLOCK_SIZE) else {
+                    // the write queue shrank all the same
+                    self.release_reserved(0);
+                    return;
+                };
+                if *state != BlockState::Writing {
+                    self.release_reserved(0);
+                    return;
+                }
+                *state = BlockState::Received;
+
+                self.release_reserved(1);
+                self.maybe_hash(piece_index);
+            }
+
+            DiskEvent::WriteFailed { piece_index, begin, error, message } => {
+                // the block is gone, so fetch it again
+                let piece = &mut self.pieces[piece_index];
+                if let Some(state) = piece.block_status.get_mut(begin / BLOCK_SIZE)
+                    && *state == BlockState::Writing
+                {
+                    *state = BlockState::NotRequested;
+                    // the running hash took in bytes that never reached disk;
+                    // the hasher reads the whole piece back instead
+                    if begin < piece.hashed {
+                        piece.hash_state = sha1::Sha1::new();
+                        piece.hashed = 0;
+                    }
+                    self.release_reserved(1);
+                }
+
+                if error == std::io::ErrorKind::StorageFull {
+                    // every further write would fail the same way
+                    if !self.paused {
+                        eprintln!("Disk full while writing piece {}, pausing download", piece_index);
+                        self.pause();
+                    }
+                } else {
+                    eprintln!("Error writing piece {}: {}", piece_index, message);
+                }
+            }
+        }
+    }
+
+    /// Hands the piece to the hasher once every block is on disk.
+    fn maybe_hash(&mut self, piece_index: usize) {
+        let piece = &mut self.pieces[piece_index];
+        if piece.is_complete || piece.is_hashing || piece.block_status.is_empty() { return; }
+        if piece.block_status.iter().any(|b| *b != BlockState::Received) { return; }
+
+        // the verdict comes back through `handle_hash_result`
+        piece.is_hashing = true;
+        let state = std::mem::take(&mut piece.hash_state);
+        // a piece that failed before needs the good blocks to compare against
+        let block_digests = !piece.suspects.is_empty();
+        self.hasher.submit(piece_index, state, piece.hashed, piece.length, self.piece_hashes[piece_index], block_digests);
+    }
+
+    /// Caps the memory held by requested and not yet written blocks.
+    /// Applies to every torrent sharing the budget.
+    pub fn set_max_buffered(&mut self, bytes: usize) {
+        self.buffers.set_max(bytes);
+        // raising the cap may let throttled peers carry on
+        self.release_reserved(0);
+    }
+
+    /// Counts this torrent's block memory against `budget`, shared with the
+    /// other torrents of the session. Call before `spawn_completion_loop`.
+    pub fn set_buffer_budget(&mut self, budget: Arc<BufferBudget>) {
+        self.buffers.release(self.reserved_blocks);
+        budget.reserve(self.reserved_blocks);
+        self.buffers = budget;
+    }
+
+    fn reserve(&mut self, blocks: usize) {
+        self.reserved_blocks += blocks;
+        self.buffers.reserve(blocks);
+    }
+
+    fn release_reserved(&mut self, blocks: usize) {
+        let blocks = blocks.min(self.reserved_blocks);
+        self.reserved_blocks -= blocks;
+        self.buffers.release(blocks);
+        if self.throttled && !self.buffers.is_full() && !self.disk.is_write_queue_full() {
+            self.throttled = false;
+            let _ = self.events.send(PieceEvent::BuffersFreed);
         }
     }
 
+    // after pieces were cleared or restored wholesale
+    fn recount_reserved(&mut self) {
+        let count = self
+            .pieces
+            .iter()
+            .flat_map(|p| &p.block_status)
+            .filter(|b| matches!(b, BlockState::Requested | BlockState::Writing))
+            .count();
+        if count > self.reserved_blocks {
+            self.reserve(count - self.reserved_blocks);
+        } else {
+            self.release_reserved(self.reserved_blocks - count);
+        }
+    }
+
+    /// Moves the download into `dir` once every wanted piece is on disk.
+    pub fn set_completed_dir(&mut self, dir: Option<PathBuf>) {
+        self.completed_dir = dir;
+    }
+
+    /// Every piece not skipped has been written.
+    pub fn is_finished(&self) -> bool {
+        self.written.iter().zip(&self.priorities).all(|(&w, &p)| w || p == Priority::Skip)
+    }
+
+    /// Starts the move to the completed directory if the download is finished.
+    /// Storage blocks reads until the files are in place, so seeding carries on from there.
+    pub fn move_if_finished(&mut self) {
+        if self.completed_dir.is_none() || !self.is_finished() { return; }
+        let Some(dir) = self.completed_dir.take() else { return };
+
+        let storage = self.storage.clone();
+        let events = self.events.clone();
+        tokio::task::spawn_blocking(move || match storage.flush().and_then(|_| storage.move_to(&dir)) {
+            Ok(()) => {
+                println!("Moved download to {}", dir.display());
+                let _ = events.send(PieceEvent::Moved(dir));
+            }
+            Err(e) => eprintln!("Failed to move download to {}: {:?}", dir.display(), e),
+        });
+    }
+
+    pub fn disk(&self) -> DiskIo {
+        self.disk.clone()
+    }
+
+    /// Stops handing out blocks. Requests already in flight still complete.
+    pub fn pause(&mut self) {
+        self.paused = true;
+        let _ = self.events.send(PieceEvent::Paused);
+    }
+
+    pub fn resume(&mut self) {
+        if !self.paused { return; }
+        self.paused = false;
+        let _ = self.events.send(PieceEvent::Resumed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
     pub fn peer_has_piece_we_dont(&self, peer_bitfield: &[bool]) -> bool {
         for (index, piece) in self.pieces.iter().enumerate() {
-            if !piece.is_complete && peer_bitfield[index] { return true; }
+            if !piece.is_complete && peer_bitfield[index] && self.priorities[index] != Priority::Skip { return true; }
         }
         false
     }
 
-    pub fn next_block(&mut self, bitfield: &[bool]) -> anyhow::Result<Option<(usize, usize, usize)>> {
-        for id in 0..self.pieces.len() {
-            if !self.pieces[id].is_complete && bitfield[id] {
-                let curr_len = self.piece_length_of_index(id);
-                self.pieces[id].maybe_init(curr_len, BLOCK_SIZE);
-                
-                for (block_index, state) in self.pieces[id].block_status.iter_mut().enumerate() {
-                    if *state == BlockState::NotRequested {
-                        *state = BlockState::Requested; 
-                        let offset = block_index * BLOCK_SIZE;
-                        return Ok(Some((id, offset, curr_len)));
-                    }
+    pub fn is_endgame(&self) -> bool {
+        self.endgame
+    }
+
+    pub fn next_block(&mut self, peer: SocketAddr, bitfield: &[bool]) -> anyhow::Result<Option<(usize, usize, usize)>> {
+        if self.paused || self.recheck.is_some() { return Ok(None); }
+        // either too much block memory or a disk that cannot keep up
+        if self.buffers.is_full() || self.disk.is_write_queue_full() {
+            self.throttled = true;
+            return Ok(None);
+        }
+
+        let order = self.piece_order(bitfield);
+
+        for &id in &order {
+            let curr_len = self.piece_length_of_index(id);
+            let piece = &mut self.pieces[id];
+            piece.maybe_init(curr_len, BLOCK_SIZE);
+
+            if let Some(block_index) = piece.block_status.iter().position(|s| *s == BlockState::NotRequested) {
+                piece.block_status[block_index] = BlockState::Requested;
+                piece.requested_from[block_index].push(peer);
+                self.reserve(1);
+                return Ok(Some((id, block_index * BLOCK_SIZE, curr_len)));
+            }
+        }
+
+        // nothing fresh for this peer; once every missing block is out somewhere,
+        // start doubling up requests so a slow peer cannot hold up the tail
+        let endgame = self
+            .pieces
+            .iter()
+            .zip(&self.priorities)
+            .all(|(p, &priority)| p.is_complete || priority == Priority::Skip || !p.has_unrequested_blocks());
+        if endgame && !self.endgame { println!("Entering endgame mode"); }
+        self.endgame = endgame;
+        if !self.endgame { return Ok(None); }
+
+        for &id in &order {
+            let piece = &mut self.pieces[id];
+
+            for (block_index, state) in piece.block_status.iter().enumerate() {
+                let requesters = &mut piece.requested_from[block_index];
+                if *state == BlockState::Requested && !requesters.contains(&peer) {
+                    requesters.push(peer);
+                    let offset = block_index * BLOCK_SIZE;
+                    return Ok(Some((id, offset, piece.length)));
                 }
             }
         }
         Ok(None)
     }
 
+    /// Incomplete pieces the peer has, in the order the picker wants them.
+    fn piece_order(&mut self, bitfield: &[bool]) -> Vec<usize> {
+        let ctx = PickerContext {
+            availability: &self.availability,
+            peer_bitfield: bitfield,
+            priorities: &self.priorities,
+            pieces: &self.pieces,
+            deadlines: &self.deadlines,
+            playback_position: self.playback_position,
+        };
+
+        let mut order = self.picker.pick(&ctx);
+        // a picker is free to be sloppy; never hand out pieces that are done or unwanted
+        order.retain(|&i| {
+            i < self.num_pieces && bitfield[i] && !self.pieces[i].is_complete && self.priorities[i] != Priority::Skip
+        });
+        order
+    }
+
+    pub fn set_picker(&mut self, picker: Box<dyn PiecePicker>) {
+        self.picker = picker;
+    }
+
+    pub fn set_piece_priority(&mut self, index: usize, priority: Priority) {
+        self.priorities[index] = priority;
+    }
+
+    pub fn piece_priority(&self, index: usize) -> Priority {
+        self.priorities[index]
+    }
+
+    /// Sets a priority per file, in torrent order. A piece gets the highest
+    /// priority of the files it overlaps, so boundary pieces of a wanted file
+    /// are still fetched even when the neighbouring file is skipped.
+    pub fn set_file_priorities(&mut self, priorities: &[Priority]) -> anyhow::Result<()> {
+        let lengths = self.storage.file_lengths();
+        if lengths.len() != priorities.len() {
+            anyhow::bail!("got {} file priorities for {} files", priorities.len(), lengths.len());
+        }
+        self.disk.set_file_priorities(priorities.to_vec());
+
+        let mut piece_priorities = vec![Priority::Skip; self.num_pieces];
+        let mut start = 0;
+        for (&length, &priority) in lengths.iter().zip(priorities) {
+            if length > 0 {
+                let first = start / self.piece_length;
+                let last = (start + length - 1) / self.piece_length;
+                for p in &mut piece_priorities[first..=last] {
+                    *p = (*p).max(priority);
+                }
+            }
+            start += length;
+        }
+
+        self.priorities = piece_priorities;
+        Ok(())
+    }
+
+    /// Asks for `index` to be fetched before `deadline`; honoured by pickers that look at deadlines.
+    pub fn set_piece_deadline(&mut self, index: usize, deadline: Instant) {
+        self.deadlines[index] = Some(deadline);
+    }
+
+    pub fn clear_piece_deadline(&mut self, index: usize) {
+        self.deadlines[index] = None;
+    }
+
+    /// Moves the streaming window so it starts at `index`. Deadlines behind the
+    /// playback position no longer matter and are dropped.
+    pub fn set_playback_position(&mut self, index: usize) {
+        let index = index.min(self.num_pieces);
+        self.deadlines[..index].fill(None);
+        self.playback_position = index;
+    }
+
+    pub fn add_peer_bitfield(&mut self, bitfield: &[bool]) {
+        for (count, &has) in self.availability.iter_mut().zip(bitfield) {
+            if has { *count += 1; }
+        }
+    }
+
+    pub fn remove_peer_bitfield(&mut self, bitfield: &[bool]) {
+        for (count, &has) in self.availability.iter_mut().zip(bitfield) {
+            if has { *count = count.saturating_sub(1); }
+        }
+    }
+
+    pub fn add_peer_have(&mut self, index: usize) {
+        self.availability[index] += 1;
+    }
+
+    /// Forgets every outstanding request made to `peer`, e.g. after it chokes us or disconnects.
+    pub fn release_requests(&mut self, peer: SocketAddr) {
+        let mut released = 0;
+        for piece in self.pieces.iter_mut().filter(|p| !p.is_complete) {
+            for (state, requesters) in piece.block_status.iter_mut().zip(piece.requested_from.iter_mut()) {
+                requesters.retain(|p| *p != peer);
+                if *state == BlockState::Requested && requesters.is_empty() {
+                    *state = BlockState::NotRequested;
+                    released += 1;
+                }
+            }
+        }
+        self.release_reserved(released);
+    }
+
+    /// Forgets a single request, e.g. after the peer rejected it.
+    pub fn release_block(&mut self, peer: SocketAddr, piece_index: usize, begin: usize) {
+        let Some(piece) = self.pieces.get_mut(piece_index) else { return };
+        let block_index = begin / BLOCK_SIZE;
+        if piece.is_complete || block_index >= piece.block_status.len() { return; }
+
+        let requesters = &mut piece.requested_from[block_index];
+        requesters.retain(|p| *p != peer);
+        if piece.block_status[block_index] == BlockState::Requested && requesters.is_empty() {
+            piece.block_status[block_index] = BlockState::NotRequested;
+            self.release_reserved(1);
+        }
+    }
+
+    /// Verified on disk, so it can be served to peers.
+    pub fn has_piece(&self, index: usize) -> bool {
+        self.written.get(index).copied().unwrap_or(false)
+    }
+
+    pub fn storage(&self) -> Arc<dyn Storage> {
+        self.storage.clone()
+    }
+
+    pub fn num_complete(&self) -> usize {
+        self.pieces.iter().filter(|p| p.is_complete).count()
+    }
+
+    /// Our verified pieces as a wire-format bitfield, high bit first.
+    pub fn compact_bitfield(&self) -> Vec<u8> {
+        let mut bitfield = vec![0u8; self.num_pieces.div_ceil(8)];
+        for (index, piece) in self.pieces.iter().enumerate() {
+            if piece.is_complete {
+                bitfield[index / 8] |= 1 << (7 - index % 8);
+            }
+        }
+        bitfield
+    }
+
+    /// Wanted bytes not yet verified on disk.
+    pub fn bytes_left(&self) -> u64 {
+        (0..self.num_pieces)
+            .filter(|&i| !self.written[i] && self.priorities[i] != Priority::Skip)
+            .map(|i| self.piece_length_of_index(i) as u64)
+            .sum()
+    }
+
+    /// Bytes of the torrent a peer with `bitfield` does not have.
+    pub fn bytes_missing(&self, bitfield: &[bool]) -> u64 {
+        bitfield
+            .iter()
+            .enumerate()
+            .filter(|(_, has)| !**has)
+            .map(|(i, _)| self.piece_length_of_index(i) as u64)
+            .sum()
+    }
+
     pub fn piece_length_of_index(&self, index: usize) -> usize {
         if index < self.num_pieces - 1 { self.piece_length }
         else { self.total_length - self.piece_length * (self.num_pieces - 1) }
     }
 
-    pub fn add_block(&mut self, piece_index: usize, begin: usize, block_data: &[u8]) -> anyhow::Result<()> {
+    /// Counts a protocol violation against `ip`, banning it after a few.
+    pub fn penalise(&mut self, ip: IpAddr) {
+        let strikes = self.strikes.entry(ip).or_insert(0);
+        *strikes += 1;
+        if *strikes >= MAX_STRIKES {
+            self.ban(ip);
+        }
+    }
+
+    pub fn ban(&mut self, ip: IpAddr) {
+        if self.banned.insert(ip) {
+            println!("Banned {ip}");
+            let _ = self.events.send(PieceEvent::Banned(ip));
+        }
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.banned.contains(&ip)
+    }
+
+    /// Checks a block against the torrent layout: the piece exists and the
+    /// block is exactly the one we would have asked for at `begin`.
+    pub fn validate_block(&self, piece_index: usize, begin: usize, length: usize) -> Result<(), BlockError> {
+        if piece_index >= self.num_pieces {
+            return Err(BlockError::UnknownPiece(piece_index));
+        }
+        if !begin.is_multiple_of(BLOCK_SIZE) {
+            return Err(BlockError::Unaligned { piece_index, begin });
+        }
+
+        let piece_len = self.piece_length_of_index(piece_index);
+        if begin >= piece_len {
+            return Err(BlockError::OutOfRange { piece_index, begin });
+        }
+        let expected = BLOCK_SIZE.min(piece_len - begin);
+        if length != expected {
+            return Err(BlockError::WrongLength { piece_index, begin, expected, got: length });
+        }
+        Ok(())
+    }
+
+    /// Takes a block the caller has checked was requested from `peer`.
+    pub fn add_block(&mut self, peer: SocketAddr, piece_index: usize, begin: usize, block_data: &[u8]) -> Result<(), BlockError> {
+        self.validate_block(piece_index, begin, block_data.len())?;
         let piece = &mut self.pieces[piece_index];
 
+        // in endgame the same block can arrive from several peers; the first one wins
+        if piece.is_complete { return Ok(()); }
+
+        // blocks for a piece that was reset underneath the request, e.g. by a recheck
         let block_index = begin / BLOCK_SIZE;
-        piece.data[begin..begin + block_data.len()].copy_from_slice(block_data);
-        piece.block_status[block_index] = BlockState::Received;
-
-        let all_received = piece.block_status.iter().all(|b| *b == BlockState::Received);
-        if all_received && !piece.is_complete {
-            let hash_matches = {
-                let data_slice = &piece.data;
-                let computed: [u8; 20] = sha1::Sha1::digest(data_slice).into();
-                computed == self.piece_hashes[piece_index]
-            };
-
-            if hash_matches {
-                piece.is_complete = true;
-                let data = std::mem::take(&mut piece.data);
-
-                let tx = self.tx.clone();
-
-                println!("{piece_index} received");
-                tokio::spawn(async move {
-                    if let Err(e) = tx.send((piece_index, data)).await {
-                        eprintln!("Failed to send {} to disk: {:?}", piece_index, e);
-                    }
-                });
-                piece.block_status.clear();
-                piece.block_status.shrink_to_fit();
-            } else {
-                // reset the blocks so they can be requested again
-                piece.block_status.fill(BlockState::NotRequested);
-                piece.is_complete = false;
-                println!("Piece {piece_index} failed hash, will retry.");
-            }
+        if block_index >= piece.block_status.len() { return Ok(()); }
+        let was_reserved = match piece.block_status[block_index] {
+            BlockState::Writing | BlockState::Received => return Ok(()),
+            BlockState::Requested => true,
+            // a late answer to a request we had already given up on
+            BlockState::NotRequested => false,
+        };
+
+        piece.block_status[block_index] = BlockState::Writing;
+        piece.contributors[block_index] = Some(peer.ip());
+        if begin == piece.hashed {
+            piece.hash_state.update(block_data);
+            piece.hashed += block_data.len();
         }
+        self.downloaded += block_data.len() as u64;
+
+        let others: Vec<SocketAddr> = std::mem::take(&mut piece.requested_from[block_index])
+            .into_iter()
+            .filter(|p| *p != peer)
+            .collect();
+
+        if !others.is_empty() {
+            // no subscribers just means no connections are left to cancel on
+            let _ = self.events.send(PieceEvent::Cancel {
+                piece_index,
+                begin,
+                length: block_data.len(),
+                peers: others,
+            });
+        }
+
+        if !was_reserved {
+            self.reserve(1);
+        }
+        // the piece gets hashed once the last of its blocks is on disk
+        self.disk.write(piece_index, begin, block_data.to_vec());
 
         Ok(())
     }
 
-}
\ No newline at end of file
+    pub fn handle_hash_result(&mut self, result: HashResult) {
+        if result.recheck {
+            self.handle_check_result(result.piece_index, result.passed);
+            return;
+        }
+
+        let piece_index = result.piece_index;
+        let piece = &mut self.pieces[piece_index];
+
+        // a recheck reset the piece while it was being hashed
+        if !piece.is_hashing { return; }
+        piece.is_hashing = false;
+
+        if result.passed {
+            // whoever sent something other than the good data for a block sent the corrupt one
+            let liars: Vec<IpAddr> = piece
+                .suspects
+                .iter()
+                .zip(&result.block_digests)
+                .flat_map(|(suspects, good)| suspects.iter().filter(move |(_, digest)| digest != good))
+                .map(|(ip, _)| *ip)
+                .collect();
+
+            piece.is_complete = true;
+            piece.clear();
+            self.written[piece_index] = true;
+            self.disk.piece_complete(piece_index);
+
+            println!("{piece_index} received");
+            let _ = self.events.send(PieceEvent::Verified(piece_index));
+            let _ = self.events.send(PieceEvent::Written(piece_index));
+            self.move_if_finished();
+
+            for ip in liars {
+                self.ban(ip);
+            }
+        } else {
+            let mut senders: Vec<Option<IpAddr>> = piece.contributors.clone();
+            senders.sort();
+            senders.dedup();
+
+            if let [Some(ip)] = senders[..] {
+                // nobody else touched the piece, so there is no one else to blame
+                println!("Piece {} failed hash, all of it from {}", piece.index, ip);
+                piece.suspects = Vec::new();
+                piece.reset();
+                self.ban(ip);
+                return;
+            }
+
+            // remember what everyone sent, to compare once the piece comes in clean
+            if piece.suspects.is_empty() {
+                piece.suspects = vec![Vec::new(); piece.block_status.len()];
+            }
+            for ((suspects, contributor), digest) in piece.suspects.iter_mut().zip(&piece.contributors).zip(&result.block_digests) {
+                if let Some(ip) = contributor
+                    && !suspects.contains(&(*ip, *digest))
+                {
+                    suspects.push((*ip, *digest));
+                }
+            }
+
+            // the blocks on disk are overwritten when they are requested again
+            piece.reset();
+            println!("Piece {} failed hash, will retry.", piece.index);
+        }
+    }
+
+    /// Pauses the download, waits for every received block to reach disk and
+    /// flushes storage, then takes the resume data. Anything taken while
+    /// blocks are still being written would look stale on the next start.
+    pub async fn stop(pm: &Arc<Mutex<PieceManager>>, info_hash: &[u8]) -> anyhow::Result<ResumeData> {
+        pm.lock().await.pause();
+        loop {
+            let guard = pm.lock().await;
+            if guard.has_pending_writes() {
+                drop(guard);
+                tokio::time::sleep(WRITE_POLL_INTERVAL).await;
+                continue;
+            }
+
+            // holding the lock keeps new blocks out until the snapshot is taken
+            let storage = guard.storage();
+            tokio::task::spawn_blocking(move || storage.flush()).await??;
+            return guard.resume_data(info_hash);
+        }
+    }
+
+    /// Some received block is still queued for the disk threads.
+    pub fn has_pending_writes(&self) -> bool {
+        self.pieces.iter().flat_map(|p| &p.block_status).any(|b| *b == BlockState::Writing)
+    }
+
+    /// Snapshot of everything needed to pick up where we left off after a restart.
+    ///
+    /// Blocks of unfinished pieces are listed only once they are on disk; ones
+    /// still queued are simply downloaded again.
+    pub fn resume_data(&self, info_hash: &[u8]) -> anyhow::Result<ResumeData> {
+        let mut pieces = vec![0u8; self.num_pieces.div_ceil(8)];
+        for (index, &written) in self.written.iter().enumerate() {
+            if written {
+                pieces[index / 8] |= 1 << (7 - index % 8);
+            }
+        }
+
+        let mut partial = Vec::new();
+        for piece in self.pieces.iter().filter(|p| !p.is_complete) {
+            let blocks: Vec<usize> = piece
+                .block_status
+                .iter()
+                .enumerate()
+                .filter(|(_, state)| **state == BlockState::Received)
+                .map(|(block_index, _)| block_index)
+                .collect();
+            if !blocks.is_empty() {
+                partial.push(PartialPiece { index: piece.index, blocks });
+            }
+        }
+
+        // read after the bitfield: a piece landing in between only makes the data look stale
+        let files = self.storage.file_states()?;
+
+        Ok(ResumeData {
+            info_hash: info_hash.to_vec(),
+            pieces,
+            partial,
+            files,
+            downloaded: self.downloaded,
+            uploaded: self.uploaded,
+        })
+    }
+
+    /// Applies resume data that has already been checked against the files on disk.
+    pub fn restore(&mut self, resume: &ResumeData) {
+        for index in 0..self.num_pieces {
+            if resume.has_piece(index) {
+                self.pieces[index].is_complete = true;
+                self.written[index] = true;
+                self.disk.piece_complete(index);
+            }
+        }
+
+        for partial in &resume.partial {
+            if partial.index >= self.num_pieces || self.pieces[partial.index].is_complete { continue; }
+
+            let curr_len = self.piece_length_of_index(partial.index);
+            let piece = &mut self.pieces[partial.index];
+            piece.maybe_init(curr_len, BLOCK_SIZE);
+
+            // the blocks themselves are already in storage
+            for &block_index in &partial.blocks {
+                if let Some(state) = piece.block_status.get_mut(block_index) {
+                    *state = BlockState::Received;
+                }
+            }
+            // the last block may have been written just before we stopped
+            self.maybe_hash(partial.index);
+        }
+
+        self.downloaded = resume.downloaded;
+        self.uploaded = resume.uploaded;
+        println!("Resumed with {}/{} pieces", self.num_complete(), self.num_pieces);
+    }
+
+    /// Hashes every piece already on disk and marks the ones that match as complete.
+    /// Returns how many pieces passed.
+    pub fn check_pieces(&mut self, mut on_progress: impl FnMut(CheckProgress)) -> usize {
+        let mut progress = CheckProgress { checked: 0, valid: 0, total: self.num_pieces };
+
+        for index in 0..self.num_pieces {
+            let curr_len = self.piece_length_of_index(index);
+            // missing or short files simply mean the piece is not there yet
+            let valid = self.storage.verify(index, curr_len, &self.piece_hashes[index]).unwrap_or(false);
+            self.apply_check(index, valid);
+
+            progress.checked += 1;
+            progress.valid += valid as usize;
+            on_progress(progress);
+        }
+
+        self.endgame = false;
+        self.recount_reserved();
+        self.release_reserved(0);
+        progress.valid
+    }
+
+    /// Rechecks a running torrent against the data on disk, throwing away
+    /// partially downloaded pieces. Pieces are hashed on the hasher threads and
+    /// the manager is only locked to take each verdict, so peers keep being
+    /// served; nothing is downloaded until the check ends. Needs the completion
+    /// loop running. Returns how many pieces passed.
+    pub async fn force_recheck(pm: Arc<Mutex<PieceManager>>, mut on_progress: impl FnMut(CheckProgress)) -> usize {
+        let mut progress = pm.lock().await.start_recheck();
+        loop {
+            let current = *progress.borrow_and_update();
+            on_progress(current);
+            if current.checked == current.total { return current.valid; }
+            // the sender goes away after the last verdict, which is already in
+            if progress.changed().await.is_err() { return progress.borrow().valid; }
+        }
+    }
+
+    /// Queues every piece with the hasher, or joins a recheck already running.
+    fn start_recheck(&mut self) -> watch::Receiver<CheckProgress> {
+        if let Some(progress) = &self.recheck {
+            return progress.subscribe();
+        }
+
+        for piece in self.pieces.iter_mut().filter(|p| !p.is_complete) {
+            piece.clear();
+        }
+        // blocks still queued for disk were dropped with their pieces
+        self.endgame = false;
+        self.recount_reserved();
+
+        let (progress, rx) = watch::channel(CheckProgress { checked: 0, valid: 0, total: self.num_pieces });
+        self.recheck = Some(progress);
+        for index in 0..self.num_pieces {
+            self.hasher.check(index, self.piece_length_of_index(index), self.piece_hashes[index]);
+        }
+        rx
+    }
+
+    fn handle_check_result(&mut self, piece_index: usize, passed: bool) {
+        let Some(progress) = &self.recheck else { return };
+        let mut current = *progress.borrow();
+        current.checked += 1;
+        current.valid += passed as usize;
+        progress.send_replace(current);
+
+        self.apply_check(piece_index, passed);
+        if current.checked == current.total {
+            self.recheck = None;
+            self.release_reserved(0);
+            let _ = self.events.send(PieceEvent::Resumed);
+        }
+    }
+
+    /// Takes a check's verdict on what is on disk for a piece.
+    fn apply_check(&mut self, index: usize, valid: bool) {
+        let piece = &mut self.pieces[index];
+        if valid {
+            if !piece.is_complete {
+                let _ = self.events.send(PieceEvent::Verified(index));
+            }
+            piece.is_complete = true;
+            piece.clear();
+            self.written[index] = true;
+            self.disk.piece_complete(index);
+        } else if piece.is_complete {
+            // was complete but the data on disk is gone or damaged
+            piece.is_complete = false;
+            self.written[index] = false;
+            self.storage.piece_invalid(index);
+        }
+    }
+
+    pub fn file_states(&self) -> anyhow::Result<Vec<FileState>> {
+        self.storage.file_states()
+    }
+}
+
+impl Drop for PieceManager {
+    fn drop(&mut self) {
+        // the budget may outlive this torrent
+        self.buffers.release(self.reserved_blocks);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pieces::file_manager::FileManager;
+    use crate::pieces::picker::{DeadlinePicker, RarestFirstPicker, SequentialPicker};
+    use crate::pieces::storage::MemoryStorage;
+    use crate::torrent::Info;
+
+    struct Harness {
+        pm: PieceManager,
+        storage: Arc<MemoryStorage>,
+        disk_rx: mpsc::UnboundedReceiver<DiskEvent>,
+        hash_rx: mpsc::UnboundedReceiver<HashResult>,
+    }
+
+    fn torrent_data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    fn harness(data: &[u8], piece_length: usize, storage: Arc<MemoryStorage>) -> Harness {
+        let hashes: Vec<u8> = data.chunks(piece_length).flat_map(|c| sha1::Sha1::digest(c).to_vec()).collect();
+        let mut pm = PieceManager::new(piece_length, data.len(), &hashes, storage.clone(), Box::new(RarestFirstPicker));
+        let disk_rx = pm.disk_events.take().unwrap();
+        let hash_rx = pm.hash_results.take().unwrap();
+        Harness { pm, storage, disk_rx, hash_rx }
+    }
+
+    fn peer(n: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, n], 6881))
+    }
+
+    impl Harness {
+        /// Takes a block and waits for it to reach storage.
+        fn deliver(&mut self, from: SocketAddr, piece_index: usize, begin: usize, data: &[u8]) {
+            // as if the block had been requested; a late answer is taken all the same
+            let length = self.pm.piece_length_of_index(piece_index);
+            self.pm.pieces[piece_index].maybe_init(length, BLOCK_SIZE);
+            self.pm.add_block(from, piece_index, begin, data).unwrap();
+            let event = self.disk_rx.blocking_recv().unwrap();
+            self.pm.handle_disk_event(event);
+        }
+
+        fn hash_verdict(&mut self) {
+            let result = self.hash_rx.blocking_recv().unwrap();
+            self.pm.handle_hash_result(result);
+        }
+    }
+
+    #[test]
+    fn hash_failure_bans_the_peer_that_sent_the_bad_block() {
+        let data = torrent_data(2 * BLOCK_SIZE);
+        let mut h = harness(&data, 2 * BLOCK_SIZE, Arc::new(MemoryStorage::new(2 * BLOCK_SIZE, data.len())));
+        let corrupt = vec![0u8; BLOCK_SIZE];
+
+        h.deliver(peer(1), 0, 0, &data[..BLOCK_SIZE]);
+        h.deliver(peer(2), 0, BLOCK_SIZE, &corrupt);
+        h.hash_verdict();
+        // two senders, so nobody can be blamed yet
+        assert!(!h.pm.has_piece(0));
+        assert!(!h.pm.is_banned(peer(2).ip()));
+
+        h.deliver(peer(1), 0, 0, &data[..BLOCK_SIZE]);
+        h.deliver(peer(3), 0, BLOCK_SIZE, &data[BLOCK_SIZE..]);
+        h.hash_verdict();
+        assert!(h.pm.has_piece(0));
+        assert!(h.pm.is_banned(peer(2).ip()));
+        assert!(!h.pm.is_banned(peer(1).ip()));
+        assert!(!h.pm.is_banned(peer(3).ip()));
+        assert_eq!(h.storage.contents(), data);
+    }
+
+    #[test]
+    fn hash_failure_from_a_single_peer_bans_it_at_once() {
+        let data = torrent_data(2 * BLOCK_SIZE);
+        let mut h = harness(&data, 2 * BLOCK_SIZE, Arc::new(MemoryStorage::new(2 * BLOCK_SIZE, data.len())));
+
+        h.deliver(peer(1), 0, 0, &data[..BLOCK_SIZE]);
+        h.deliver(peer(1), 0, BLOCK_SIZE, &vec![0u8; BLOCK_SIZE]);
+        h.hash_verdict();
+        assert!(!h.pm.has_piece(0));
+        assert!(h.pm.is_banned(peer(1).ip()));
+    }
+
+    #[test]
+    fn endgame_starts_with_skipped_pieces() {
+        let data = torrent_data(3 * BLOCK_SIZE);
+        let mut h = harness(&data, BLOCK_SIZE, Arc::new(MemoryStorage::new(BLOCK_SIZE, data.len())));
+        h.pm.set_piece_priority(2, Priority::Skip);
+        let everything = vec![true; 3];
+
+        let first = h.pm.next_block(peer(1), &everything).unwrap().unwrap();
+        let second = h.pm.next_block(peer(1), &everything).unwrap().unwrap();
+        assert_ne!(first.0, second.0);
+        assert!(!h.pm.is_endgame());
+
+        // nothing new for the first peer and it already has both requests out
+        assert_eq!(h.pm.next_block(peer(1), &everything).unwrap(), None);
+        assert!(h.pm.is_endgame());
+
+        let duplicate = h.pm.next_block(peer(2), &everything).unwrap().unwrap();
+        assert!(duplicate.0 == first.0 || duplicate.0 == second.0);
+    }
+
+    #[test]
+    fn resume_data_restores_complete_and_partial_pieces() {
+        let piece_length = 2 * BLOCK_SIZE;
+        let data = torrent_data(2 * piece_length);
+        let storage = Arc::new(MemoryStorage::new(piece_length, data.len()));
+        let mut h = harness(&data, piece_length, storage.clone());
+
+        h.deliver(peer(1), 0, 0, &data[..BLOCK_SIZE]);
+        h.deliver(peer(1), 0, BLOCK_SIZE, &data[BLOCK_SIZE..piece_length]);
+        h.hash_verdict();
+        h.deliver(peer(1), 1, 0, &data[piece_length..piece_length + BLOCK_SIZE]);
+        let resume = h.pm.resume_data(&[0; 20]).unwrap();
+        assert!(resume.has_piece(0));
+        assert!(!resume.has_piece(1));
+
+        let mut h = harness(&data, piece_length, storage);
+        h.pm.restore(&resume);
+        assert!(h.pm.has_piece(0));
+        assert!(!h.pm.has_piece(1));
+        assert_eq!(h.pm.pieces[1].blocks_received(), 1);
+
+        // the restored block is read back from storage for the hash
+        h.deliver(peer(2), 1, BLOCK_SIZE, &data[piece_length + BLOCK_SIZE..]);
+        h.hash_verdict();
+        assert!(h.pm.has_piece(1));
+        assert!(h.pm.is_finished());
+    }
+
+    #[tokio::test]
+    async fn resume_data_taken_on_stop_survives_a_restart() {
+        let piece_length = 2 * BLOCK_SIZE;
+        let data = torrent_data(3 * piece_length);
+        let hashes: Vec<u8> = data.chunks(piece_length).flat_map(|c| sha1::Sha1::digest(c).to_vec()).collect();
+        let info = Info { name: "stop.bin".into(), piece_length, pieces: hashes.clone(), length: Some(data.len()), files: None };
+        let dir = std::env::temp_dir().join(format!("torrent-rs-stop-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let info_hash = [7; 20];
+
+        let storage = Arc::new(FileManager::new(&info, &dir).unwrap());
+        let pm = Arc::new(Mutex::new(PieceManager::new(piece_length, data.len(), &hashes, storage, Box::new(RarestFirstPicker))));
+        PieceManager::spawn_completion_loop(pm.clone());
+        {
+            // a whole piece and half of another, still queued for the disk
+            let mut pm = pm.lock().await;
+            for (piece_index, begin) in [(0, 0), (0, BLOCK_SIZE), (1, 0)] {
+                pm.pieces[piece_index].maybe_init(piece_length, BLOCK_SIZE);
+                let start = piece_index * piece_length + begin;
+                pm.add_block(peer(1), piece_index, begin, &data[start..start + BLOCK_SIZE]).unwrap();
+            }
+        }
+        let resume = PieceManager::stop(&pm, &info_hash).await.unwrap();
+        let resume_path = dir.join(".resume");
+        resume.save(&resume_path).unwrap();
+
+        let storage = Arc::new(FileManager::new(&info, &dir).unwrap());
+        let mut restarted = PieceManager::new(piece_length, data.len(), &hashes, storage, Box::new(RarestFirstPicker));
+        let resume = ResumeData::load(&resume_path).unwrap().unwrap();
+        assert!(resume.is_valid_for(&info_hash, 3, &restarted.file_states().unwrap()));
+        restarted.restore(&resume);
+        assert!(restarted.pieces[0].is_complete() || restarted.pieces[0].is_hashing());
+        assert_eq!(restarted.pieces[1].blocks_received(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn force_recheck_finds_pieces_on_disk() {
+        let data = torrent_data(3 * BLOCK_SIZE);
+        let storage = Arc::new(MemoryStorage::new(BLOCK_SIZE, data.len()));
+        storage.write_block(0, 0, &data[..BLOCK_SIZE]).unwrap();
+        storage.write_block(2, 0, &data[2 * BLOCK_SIZE..]).unwrap();
+
+        let hashes: Vec<u8> = data.chunks(BLOCK_SIZE).flat_map(|c| sha1::Sha1::digest(c).to_vec()).collect();
+        let pm = PieceManager::new(BLOCK_SIZE, data.len(), &hashes, storage, Box::new(RarestFirstPicker));
+        let pm = Arc::new(Mutex::new(pm));
+        PieceManager::spawn_completion_loop(pm.clone());
+
+        let mut last = None;
+        let valid = PieceManager::force_recheck(pm.clone(), |p| last = Some(p)).await;
+        assert_eq!(valid, 2);
+        assert_eq!(last.map(|p| p.checked), Some(3));
+
+        let pm = pm.lock().await;
+        assert!(pm.has_piece(0) && !pm.has_piece(1) && pm.has_piece(2));
+    }
+
+    /// Pieces in the order `next_block` hands them to one peer; one block per piece.
+    fn request_order(pm: &mut PieceManager, bitfield: &[bool]) -> Vec<usize> {
+        std::iter::from_fn(|| pm.next_block(peer(1), bitfield).unwrap().map(|(piece, _, _)| piece)).collect()
+    }
+
+    #[test]
+    fn pickers_rank_pieces_by_rarity_index_and_deadline() {
+        let data = torrent_data(4 * BLOCK_SIZE);
+        let everything = vec![true; 4];
+        let new = || harness(&data, BLOCK_SIZE, Arc::new(MemoryStorage::new(BLOCK_SIZE, data.len())));
+
+        // availability 1, 3, 2, 3
+        let mut h = new();
+        h.pm.add_peer_bitfield(&everything);
+        h.pm.add_peer_bitfield(&[false, true, true, true]);
+        h.pm.add_peer_bitfield(&[false, true, false, true]);
+        h.pm.set_piece_priority(3, Priority::High);
+        assert_eq!(request_order(&mut h.pm, &everything), [3, 0, 2, 1]);
+
+        let mut h = new();
+        h.pm.add_peer_bitfield(&[false, true, true, true]);
+        h.pm.set_picker(Box::new(SequentialPicker));
+        h.pm.set_piece_priority(2, Priority::Low);
+        assert_eq!(request_order(&mut h.pm, &[true, true, true, false]), [0, 1, 2]);
+
+        let mut h = new();
+        h.pm.add_peer_bitfield(&[true, true, false, true]);
+        h.pm.set_picker(Box::new(DeadlinePicker::new(1)));
+        h.pm.set_playback_position(1);
+        h.pm.set_piece_deadline(3, Instant::now());
+        // the deadline, then the window, then rarest first
+        assert_eq!(request_order(&mut h.pm, &everything), [3, 1, 2, 0]);
+    }
+
+    // ranks pieces back to front and throws in ones the manager must ignore
+    struct Backwards;
+
+    impl PiecePicker for Backwards {
+        fn pick(&mut self, ctx: &PickerContext<'_>) -> Vec<usize> {
+            let mut order: Vec<usize> = (0..ctx.pieces.len() + 2).rev().collect();
+            order.push(0);
+            order
+        }
+    }
+
+    #[test]
+    fn custom_picker_order_is_followed_but_sanitised() {
+        let data = torrent_data(5 * BLOCK_SIZE);
+        let mut h = harness(&data, BLOCK_SIZE, Arc::new(MemoryStorage::new(BLOCK_SIZE, data.len())));
+        h.pm.set_picker(Box::new(Backwards));
+        h.pm.set_piece_priority(2, Priority::Skip);
+        h.deliver(peer(2), 1, 0, &data[BLOCK_SIZE..2 * BLOCK_SIZE]);
+        h.hash_verdict();
+
+        // out of range, skipped, complete and pieces the peer lacks are all dropped
+        assert_eq!(request_order(&mut h.pm, &[true, true, true, true, false]), [3, 0]);
+    }
+
+    #[test]
+    fn blocks_must_match_the_torrent_layout() {
+        // the first piece ends in a short block, the last piece is shorter still
+        let piece_length = 2 * BLOCK_SIZE + 100;
+        let data = torrent_data(piece_length + BLOCK_SIZE + 10);
+        let mut h = harness(&data, piece_length, Arc::new(MemoryStorage::new(piece_length, data.len())));
+
+        assert_eq!(h.pm.validate_block(2, 0, BLOCK_SIZE), Err(BlockError::UnknownPiece(2)));
+        assert_eq!(h.pm.validate_block(0, 1, BLOCK_SIZE), Err(BlockError::Unaligned { piece_index: 0, begin: 1 }));
+        assert_eq!(
+            h.pm.validate_block(0, 3 * BLOCK_SIZE, BLOCK_SIZE),
+            Err(BlockError::OutOfRange { piece_index: 0, begin: 3 * BLOCK_SIZE })
+        );
+        assert_eq!(
+            h.pm.validate_block(0, 2 * BLOCK_SIZE, BLOCK_SIZE),
+            Err(BlockError::WrongLength { piece_index: 0, begin: 2 * BLOCK_SIZE, expected: 100, got: BLOCK_SIZE })
+        );
+        assert_eq!(h.pm.validate_block(0, 2 * BLOCK_SIZE, 100), Ok(()));
+        assert_eq!(h.pm.validate_block(1, BLOCK_SIZE, 10), Ok(()));
+
+        // a rejected block never reaches storage
+        assert!(h.pm.add_block(peer(1), 1, BLOCK_SIZE, &[0; 11]).is_err());
+        assert!(h.disk_rx.try_recv().is_err());
+        assert_eq!(h.pm.bytes_left(), data.len() as u64);
+    }
+
+    #[test]
+    fn disk_full_pauses_and_the_block_is_fetched_again() {
+        let data = torrent_data(2 * BLOCK_SIZE);
+        let mut h = harness(&data, BLOCK_SIZE, Arc::new(MemoryStorage::new(BLOCK_SIZE, data.len())));
+        let everything = vec![true; 2];
+
+        let (piece_index, begin, _) = h.pm.next_block(peer(1), &everything).unwrap().unwrap();
+        h.pm.add_block(peer(1), piece_index, begin, &data[..BLOCK_SIZE]).unwrap();
+        // as if the write the disk threads are doing had failed
+        h.pm.handle_disk_event(DiskEvent::WriteFailed {
+            piece_index,
+            begin,
+            error: std::io::ErrorKind::StorageFull,
+            message: "No space left on device".into(),
+        });
+
+        assert!(h.pm.is_paused());
+        assert_eq!(h.pm.next_block(peer(1), &everything).unwrap(), None);
+
+        h.pm.resume();
+        let again = h.pm.next_block(peer(2), &everything).unwrap().unwrap();
+        assert_eq!((again.0, again.1), (piece_index, begin));
+    }
+
+    #[test]
+    fn torrents_share_one_buffer_budget() {
+        let data = torrent_data(2 * BLOCK_SIZE);
+        let budget = BufferBudget::new(BLOCK_SIZE);
+        let mut a = harness(&data, BLOCK_SIZE, Arc::new(MemoryStorage::new(BLOCK_SIZE, data.len())));
+        let mut b = harness(&data, BLOCK_SIZE, Arc::new(MemoryStorage::new(BLOCK_SIZE, data.len())));
+        a.pm.set_buffer_budget(budget.clone());
+        b.pm.set_buffer_budget(budget.clone());
+        let freed = budget.freed.subscribe();
+        let everything = vec![true; 2];
+
+        let (piece_index, begin, _) = a.pm.next_block(peer(1), &everything).unwrap().unwrap();
+        assert!(budget.is_full());
+        // the other torrent has nothing in flight and still has to wait
+        assert_eq!(b.pm.next_block(peer(2), &everything).unwrap(), None);
+
+        a.deliver(peer(1), piece_index, begin, &data[..BLOCK_SIZE]);
+        assert_eq!(budget.used(), 0);
+        assert!(freed.has_changed().unwrap());
+        assert!(b.pm.next_block(peer(2), &everything).unwrap().is_some());
+
+        // a torrent going away gives back what it held
+        drop(b);
+        assert_eq!(budget.used(), 0);
+    }
+}
@@ -0,0 +1,72 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Size and modification time of one output file when resume data was saved.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct FileState {
+    pub length: u64,
+    pub mtime: u64,
+}
+
+/// Blocks of a piece that had not finished when resume data was saved. The
+/// data itself is already in storage.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PartialPiece {
+    pub index: usize,
+    pub blocks: Vec<usize>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ResumeData {
+    #[serde(with = "serde_bytes")]
+    pub info_hash: Vec<u8>,
+    // verified pieces, same layout as the wire bitfield
+    #[serde(with = "serde_bytes")]
+    pub pieces: Vec<u8>,
+    pub partial: Vec<PartialPiece>,
+    pub files: Vec<FileState>,
+    pub downloaded: u64,
+    pub uploaded: u64,
+}
+
+impl ResumeData {
+    pub fn load(path: &Path) -> anyhow::Result<Option<Self>> {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Some(serde_bencode::from_bytes(&bytes)?))
+    }
+
+    /// Writes to a temporary file first so a crash never leaves half a resume file behind.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let bytes = serde_bencode::to_bytes(self)?;
+        let tmp = path.with_extension("resume.tmp");
+        // nothing may have been downloaded into the save path yet
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&tmp, bytes)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Resume data can only be trusted if it is for this torrent and nobody
+    /// touched the files since it was written.
+    pub fn is_valid_for(&self, info_hash: &[u8], num_pieces: usize, files: &[FileState]) -> bool {
+        self.info_hash == info_hash
+            && self.pieces.len() == num_pieces.div_ceil(8)
+            && self.files == files
+    }
+
+    pub fn has_piece(&self, index: usize) -> bool {
+        self.pieces.get(index / 8).is_some_and(|b| b & (1 << (7 - index % 8)) != 0)
+    }
+}
+
+/// Hidden file in the save path, so it is found whatever directory we run from.
+pub fn resume_path(save_path: &Path, info_hash: &[u8]) -> PathBuf {
+    let hex: String = info_hash.iter().map(|b| format!("{b:02x}")).collect();
+    save_path.join(format!(".{hex}.resume"))
+}
@@ -0,0 +1,190 @@
+use std::collections::HashSet;
+
+// most filesystems cap a single name at 255 bytes; storage may still wrap a
+// name as `{name}.part` or `.{name}.parts`, so leave room for that
+const MAX_COMPONENT_LEN: usize = 255 - ".".len() - ".parts".len();
+
+// names Windows refuses no matter the extension
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Turns the path list of a torrent file into a relative path that cannot
+/// leave the save directory.
+///
+/// `..` and absolute components are rejected outright, empty and `.`
+/// components are dropped, reserved names get a `_` prefix, separators and
+/// colons inside a component are replaced and overlong names are truncated.
+pub fn sanitize_path(components: &[String]) -> anyhow::Result<String> {
+    let mut parts = Vec::with_capacity(components.len());
+
+    for component in components {
+        match component.as_str() {
+            "" | "." => continue,
+            ".." => anyhow::bail!("path {:?} tries to leave the download directory", components),
+            _ => {}
+        }
+        if component.starts_with('/') || component.starts_with('\\') || is_drive_prefix(component) {
+            anyhow::bail!("path {:?} has an absolute component", components);
+        }
+        parts.push(sanitize_component(component));
+    }
+
+    if parts.is_empty() {
+        anyhow::bail!("path {:?} is empty", components);
+    }
+    Ok(parts.join("/"))
+}
+
+/// `C:` on its own or followed by a separator; `1:1 backup.csv` is just a name.
+fn is_drive_prefix(component: &str) -> bool {
+    let bytes = component.as_bytes();
+    bytes.len() >= 2
+        && bytes[0].is_ascii_alphabetic()
+        && bytes[1] == b':'
+        && matches!(bytes.get(2), None | Some(b'/') | Some(b'\\'))
+}
+
+fn sanitize_component(component: &str) -> String {
+    let mut name: String = component
+        .chars()
+        .map(|c| if c == '/' || c == '\\' || c == ':' || c.is_control() { '_' } else { c })
+        .collect();
+
+    let stem = name.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+        name.insert(0, '_');
+    }
+
+    truncate_name(&name, MAX_COMPONENT_LEN)
+}
+
+/// Shortens `name` to at most `max` bytes, keeping a short extension.
+fn truncate_name(name: &str, max: usize) -> String {
+    if name.len() <= max {
+        return name.to_string();
+    }
+
+    let (stem, ext) = split_extension(name);
+    format!("{}{}", truncate_str(stem, max - ext.len()), ext)
+}
+
+// a leading dot is a hidden file, not an extension; long "extensions" are just names with dots
+fn split_extension(name: &str) -> (&str, &str) {
+    match name.rfind('.') {
+        Some(dot) if dot > 0 && name.len() - dot <= 16 => name.split_at(dot),
+        _ => (name, ""),
+    }
+}
+
+fn truncate_str(s: &str, max: usize) -> &str {
+    let mut end = max.min(s.len());
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+/// Makes every path unique, case-insensitively, by appending ` (n)` to later
+/// duplicates. Runs in torrent order, so the result is the same every time.
+pub fn dedup_paths(paths: &mut [String]) {
+    let mut taken: HashSet<String> = HashSet::new();
+    // directories are taken too, a file cannot share a name with one
+    for path in paths.iter() {
+        let mut dir = String::new();
+        for part in path.split('/').rev().skip(1).collect::<Vec<_>>().into_iter().rev() {
+            if !dir.is_empty() { dir.push('/'); }
+            dir.push_str(part);
+            taken.insert(dir.to_lowercase());
+        }
+    }
+
+    for path in paths.iter_mut() {
+        if taken.insert(path.to_lowercase()) {
+            continue;
+        }
+
+        let (dir, name) = path.split_at(path.rfind('/').map_or(0, |s| s + 1));
+        let (stem, ext) = split_extension(name);
+
+        let mut n = 1;
+        let unique = loop {
+            // the suffix must fit in the name limit as well
+            let suffix = format!(" ({n})");
+            let stem = truncate_str(stem, MAX_COMPONENT_LEN - suffix.len() - ext.len());
+            let candidate = format!("{dir}{stem}{suffix}{ext}");
+            if taken.insert(candidate.to_lowercase()) {
+                break candidate;
+            }
+            n += 1;
+        };
+        *path = unique;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(components: &[&str]) -> anyhow::Result<String> {
+        sanitize_path(&components.iter().map(|c| c.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn rejects_paths_leaving_the_save_directory() {
+        assert!(path(&["a", "..", "b"]).is_err());
+        assert!(path(&["/etc", "passwd"]).is_err());
+        assert!(path(&["\\\\server", "share"]).is_err());
+        assert!(path(&["C:", "Windows"]).is_err());
+        assert!(path(&["c:\\Windows"]).is_err());
+        assert!(path(&["", "."]).is_err());
+    }
+
+    #[test]
+    fn cleans_up_components() {
+        assert_eq!(path(&["dir", "", ".", "file.txt"]).unwrap(), "dir/file.txt");
+        assert_eq!(path(&["a/b\\c\n"]).unwrap(), "a_b_c_");
+        assert_eq!(path(&["con.txt"]).unwrap(), "_con.txt");
+        assert_eq!(path(&["LPT1"]).unwrap(), "_LPT1");
+        assert_eq!(path(&["console.txt"]).unwrap(), "console.txt");
+        // a colon is only a drive when it follows a lone letter
+        assert_eq!(path(&["1:1 backup.csv"]).unwrap(), "1_1 backup.csv");
+        assert_eq!(path(&["a:b"]).unwrap(), "a_b");
+    }
+
+    #[test]
+    fn truncates_long_names_keeping_the_extension() {
+        let name = format!("{}.mkv", "x".repeat(300));
+        let cleaned = path(&[&name]).unwrap();
+        assert_eq!(cleaned.len(), MAX_COMPONENT_LEN);
+        assert!(cleaned.ends_with(".mkv"));
+        // still fits once storage adds `.part`
+        assert!(cleaned.len() + ".part".len() <= 255);
+
+        // never splits a multi-byte character
+        let cleaned = path(&["é".repeat(200).as_str()]).unwrap();
+        assert!(cleaned.len() <= MAX_COMPONENT_LEN);
+        assert!(cleaned.chars().all(|c| c == 'é'));
+    }
+
+    #[test]
+    fn dedups_case_insensitively_and_against_directories() {
+        let mut paths: Vec<String> = ["a.txt", "A.TXT", "a.txt", "dir/f", "DIR"].iter().map(|p| p.to_string()).collect();
+        dedup_paths(&mut paths);
+        assert_eq!(paths, ["a.txt", "A (1).TXT", "a (2).txt", "dir/f", "DIR (1)"]);
+    }
+
+    #[test]
+    fn dedup_keeps_truncated_names_within_the_limit() {
+        let name = format!("{}.mkv", "x".repeat(300));
+        let mut paths = vec![path(&["dir", &name]).unwrap(); 2];
+        dedup_paths(&mut paths);
+
+        let renamed = paths[1].strip_prefix("dir/").unwrap();
+        assert!(renamed.ends_with(" (1).mkv"));
+        assert!(renamed.len() <= MAX_COMPONENT_LEN);
+        assert_ne!(paths[0], paths[1]);
+    }
+}
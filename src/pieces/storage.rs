@@ -0,0 +1,168 @@
+use sha1::{self, Digest};
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::pieces::picker::Priority;
+use crate::pieces::resume::FileState;
+
+/// How disk space for the torrent is reserved before downloading.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum AllocationMode {
+    /// Files are created and grown as pieces arrive.
+    #[default]
+    Lazy,
+    /// Files are set to their final size up front without reserving blocks.
+    Sparse,
+    /// Every block is reserved up front, so the disk cannot fill up mid-download.
+    Full,
+}
+
+impl std::str::FromStr for AllocationMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "lazy" | "none" => Ok(Self::Lazy),
+            "sparse" => Ok(Self::Sparse),
+            "full" => Ok(Self::Full),
+            _ => anyhow::bail!("unknown allocation mode {:?}, expected lazy, sparse or full", s),
+        }
+    }
+}
+
+/// Where piece data lives. `FileManager` is the default; anything that can map
+/// `(piece, begin, length)` ranges onto bytes can stand in for it.
+pub trait Storage: Send + Sync {
+    fn read_block(&self, piece_index: usize, begin: usize, length: usize) -> anyhow::Result<Vec<u8>>;
+
+    /// Writes `data` at `begin` within the piece. Blocks are written as they
+    /// arrive, before the piece they belong to has been verified.
+    fn write_block(&self, piece_index: usize, begin: usize, data: &[u8]) -> anyhow::Result<()>;
+
+    /// Like `read_block`, for data that is about to be hashed rather than
+    /// served; storage with a read cache should leave it untouched.
+    fn read_uncached(&self, piece_index: usize, begin: usize, length: usize) -> anyhow::Result<Vec<u8>> {
+        self.read_block(piece_index, begin, length)
+    }
+
+    fn flush(&self) -> anyhow::Result<()>;
+
+    /// Reads the piece back and checks it against its expected SHA-1.
+    fn verify(&self, piece_index: usize, length: usize, hash: &[u8; 20]) -> anyhow::Result<bool> {
+        let data = self.read_uncached(piece_index, 0, length)?;
+        let computed: [u8; 20] = sha1::Sha1::digest(&data).into();
+        Ok(computed == *hash)
+    }
+
+    /// Reserves space for the data according to `mode`, failing early if the
+    /// disk cannot hold what is still missing.
+    fn allocate(&self, _mode: AllocationMode) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Relocates the data; later reads and writes go to the new place.
+    fn move_to(&self, dest: &Path) -> anyhow::Result<()>;
+
+    fn delete(&self) -> anyhow::Result<()>;
+
+    /// Tells the storage a piece is on disk and verified, whether it was just
+    /// downloaded or found by resume data or a recheck.
+    fn piece_complete(&self, _piece_index: usize) {}
+
+    /// Takes back `piece_complete` after a recheck found the piece damaged or missing.
+    fn piece_invalid(&self, _piece_index: usize) {}
+
+    /// Caps how many file handles the storage keeps open at once.
+    fn set_max_open_files(&self, _limit: usize) {}
+
+    /// Length of every file in the torrent, in torrent order.
+    fn file_lengths(&self) -> Vec<usize>;
+
+    /// Storage that understands files can keep skipped ones off disk. The
+    /// default stores everything.
+    fn set_file_priorities(&self, _priorities: &[Priority]) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Size and mtime of the backing files, used to tell whether resume data is stale.
+    fn file_states(&self) -> anyhow::Result<Vec<FileState>> {
+        Ok(Vec::new())
+    }
+}
+
+/// Keeps the whole torrent in memory. Useful for tests and short-lived downloads.
+pub struct MemoryStorage {
+    piece_length: usize,
+    data: Mutex<Vec<u8>>,
+}
+
+impl MemoryStorage {
+    pub fn new(piece_length: usize, total_length: usize) -> Self {
+        Self {
+            piece_length,
+            data: Mutex::new(vec![0; total_length]),
+        }
+    }
+
+    /// Copy of everything written so far.
+    pub fn contents(&self) -> Vec<u8> {
+        self.data.lock().unwrap().clone()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn read_block(&self, piece_index: usize, begin: usize, length: usize) -> anyhow::Result<Vec<u8>> {
+        let data = self.data.lock().unwrap();
+        let start = piece_index * self.piece_length + begin;
+        match data.get(start..start + length) {
+            Some(block) => Ok(block.to_vec()),
+            None => anyhow::bail!("piece {piece_index} runs past the end of the torrent"),
+        }
+    }
+
+    fn write_block(&self, piece_index: usize, begin: usize, block: &[u8]) -> anyhow::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        let start = piece_index * self.piece_length + begin;
+        match data.get_mut(start..start + block.len()) {
+            Some(dest) => dest.copy_from_slice(block),
+            None => anyhow::bail!("piece {piece_index} runs past the end of the torrent"),
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn move_to(&self, _dest: &Path) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn delete(&self) -> anyhow::Result<()> {
+        self.data.lock().unwrap().fill(0);
+        Ok(())
+    }
+
+    fn file_lengths(&self) -> Vec<usize> {
+        vec![self.data.lock().unwrap().len()]
+    }
+}
+
+/// Bytes available to unprivileged users on the filesystem holding `path`.
+#[cfg(unix)]
+pub fn free_space(path: &Path) -> anyhow::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `c_path` is NUL-terminated and `stat` is a valid out-pointer
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn free_space(_path: &Path) -> anyhow::Result<u64> {
+    Ok(u64::MAX)
+}
@@ -0,0 +1,180 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// time constant of the moving averages: older traffic fades out over about this long
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Byte counters for one peer, torrent or the whole session, split into
+/// payload (piece data) and protocol overhead, with moving-average payload rates.
+pub struct TransferStats {
+    payload_down: AtomicU64,
+    payload_up: AtomicU64,
+    overhead_down: AtomicU64,
+    overhead_up: AtomicU64,
+    rates: Mutex<Rates>,
+}
+
+struct Rates {
+    last_sample: Instant,
+    last_down: u64,
+    last_up: u64,
+    down: f64,
+    up: f64,
+}
+
+/// Point-in-time copy of a [`TransferStats`]. Rates are payload bytes per second.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StatsSnapshot {
+    pub payload_downloaded: u64,
+    pub payload_uploaded: u64,
+    pub overhead_downloaded: u64,
+    pub overhead_uploaded: u64,
+    pub download_rate: f64,
+    pub upload_rate: f64,
+}
+
+impl TransferStats {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            payload_down: AtomicU64::new(0),
+            payload_up: AtomicU64::new(0),
+            overhead_down: AtomicU64::new(0),
+            overhead_up: AtomicU64::new(0),
+            rates: Mutex::new(Rates { last_sample: Instant::now(), last_down: 0, last_up: 0, down: 0.0, up: 0.0 }),
+        })
+    }
+
+    pub fn record_download(&self, payload: usize, overhead: usize) {
+        self.payload_down.fetch_add(payload as u64, Ordering::Relaxed);
+        self.overhead_down.fetch_add(overhead as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_upload(&self, payload: usize, overhead: usize) {
+        self.payload_up.fetch_add(payload as u64, Ordering::Relaxed);
+        self.overhead_up.fetch_add(overhead as u64, Ordering::Relaxed);
+    }
+
+    /// Current totals. Also folds the traffic since the last call into the rates,
+    /// so polling it regularly gives smoother numbers.
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let payload_downloaded = self.payload_down.load(Ordering::Relaxed);
+        let payload_uploaded = self.payload_up.load(Ordering::Relaxed);
+
+        let mut rates = self.rates.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(rates.last_sample).as_secs_f64();
+        if elapsed > 0.0 {
+            // exponential moving average that copes with uneven sampling
+            let weight = 1.0 - (-elapsed / RATE_WINDOW.as_secs_f64()).exp();
+            let down = (payload_downloaded - rates.last_down) as f64 / elapsed;
+            let up = (payload_uploaded - rates.last_up) as f64 / elapsed;
+            rates.down += weight * (down - rates.down);
+            rates.up += weight * (up - rates.up);
+            rates.last_sample = now;
+            rates.last_down = payload_downloaded;
+            rates.last_up = payload_uploaded;
+        }
+
+        StatsSnapshot {
+            payload_downloaded,
+            payload_uploaded,
+            overhead_downloaded: self.overhead_down.load(Ordering::Relaxed),
+            overhead_uploaded: self.overhead_up.load(Ordering::Relaxed),
+            download_rate: rates.down,
+            upload_rate: rates.up,
+        }
+    }
+}
+
+/// The counters one connection's traffic is added to: its own, its torrent's and the session's.
+#[derive(Clone, Default)]
+pub struct ConnectionStats {
+    counters: Vec<Arc<TransferStats>>,
+}
+
+impl ConnectionStats {
+    pub fn new(peer: Arc<TransferStats>, torrent: Arc<TransferStats>, session: Arc<TransferStats>) -> Self {
+        Self { counters: vec![peer, torrent, session] }
+    }
+
+    pub fn record_download(&self, payload: usize, overhead: usize) {
+        self.counters.iter().for_each(|c| c.record_download(payload, overhead));
+    }
+
+    pub fn record_upload(&self, payload: usize, overhead: usize) {
+        self.counters.iter().for_each(|c| c.record_upload(payload, overhead));
+    }
+}
+
+/// Everything there is to know about one torrent's transfer.
+#[derive(Clone, Debug)]
+pub struct TorrentStats {
+    pub transfer: StatsSnapshot,
+    /// Wanted bytes not yet verified on disk.
+    pub left: u64,
+    /// At the current download rate; `None` while nothing is coming in.
+    pub eta: Option<Duration>,
+    pub peers: Vec<PeerStats>,
+}
+
+#[derive(Clone, Debug)]
+pub struct PeerStats {
+    pub addr: SocketAddr,
+    pub transfer: StatsSnapshot,
+}
+
+/// How long `left` bytes take at `rate` bytes per second.
+pub fn eta(left: u64, rate: f64) -> Option<Duration> {
+    if left == 0 { return Some(Duration::ZERO); }
+    // below a byte a second the estimate is meaningless
+    if rate < 1.0 { return None; }
+    Some(Duration::from_secs_f64(left as f64 / rate))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connection_traffic_reaches_every_level() {
+        let (peer, torrent, session) = (TransferStats::new(), TransferStats::new(), TransferStats::new());
+        let other = TransferStats::new();
+        ConnectionStats::new(peer.clone(), torrent.clone(), session.clone()).record_download(16384, 13);
+        ConnectionStats::new(other.clone(), torrent.clone(), session.clone()).record_upload(100, 5);
+
+        let snapshot = peer.snapshot();
+        assert_eq!((snapshot.payload_downloaded, snapshot.overhead_downloaded), (16384, 13));
+        assert_eq!((snapshot.payload_uploaded, snapshot.overhead_uploaded), (0, 0));
+
+        let snapshot = session.snapshot();
+        assert_eq!((snapshot.payload_downloaded, snapshot.overhead_downloaded), (16384, 13));
+        assert_eq!((snapshot.payload_uploaded, snapshot.overhead_uploaded), (100, 5));
+    }
+
+    #[test]
+    fn rates_average_payload_only() {
+        let stats = TransferStats::new();
+        stats.record_download(0, 1_000_000);
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(stats.snapshot().download_rate, 0.0);
+
+        stats.record_download(10_000, 0);
+        std::thread::sleep(Duration::from_millis(50));
+        let first = stats.snapshot().download_rate;
+        // a burst only moves the average part of the way
+        assert!(first > 0.0 && first < 10_000.0 / 0.05, "{first}");
+
+        stats.record_download(10_000, 0);
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(stats.snapshot().download_rate > first);
+    }
+
+    #[test]
+    fn eta_needs_a_real_rate() {
+        assert_eq!(eta(0, 0.0), Some(Duration::ZERO));
+        assert_eq!(eta(1000, 0.5), None);
+        assert_eq!(eta(1000, 100.0), Some(Duration::from_secs(10)));
+    }
+}